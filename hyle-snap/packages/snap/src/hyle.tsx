@@ -53,6 +53,8 @@ export interface ProofTransaction {
 }
 
 export const contract_name: Identity = 'mmid';
+// Network id the contract was registered with, bound into every signed message
+export const network_id = 'hyle-devnet';
 export const HYLE_NODE_URL = 'http://localhost:4321';
 export const HYLE_PROVER_URL = 'http://localhost:4000';
 
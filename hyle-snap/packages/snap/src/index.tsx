@@ -7,8 +7,9 @@ import {
   MethodNotFoundError,
 } from '@metamask/snaps-sdk';
 import { Box, Heading, Text, Divider } from '@metamask/snaps-sdk/jsx';
+import { keccak_256 } from '@noble/hashes/sha3';
 
-import { Blob, contract_name, HYLE_NODE_URL, HYLE_PROVER_URL } from './hyle';
+import { Blob, contract_name, network_id, HYLE_NODE_URL, HYLE_PROVER_URL } from './hyle';
 import { AmmAction, deserializeAmmAction, deserializeERC20Action, ERC20Action } from './model';
 
 async function getAccount(): Promise<{ account: string, nonce: number }> {
@@ -55,11 +56,55 @@ async function setNonce(nonce: number) {
   });
 }
 
+// Blobs being signed, shown by the signature insight
+async function setPendingBlobs(blobs: Array<Blob>) {
+  const { account, nonce } = await getAccount();
+  await snap.request({
+    method: 'snap_manageState',
+    params: { operation: 'update', newState: { account, nonce, pendingBlobs: blobs } },
+  });
+}
+
+async function getPendingBlobs(): Promise<Array<Blob>> {
+  const state = await snap.request({
+    method: 'snap_manageState',
+    params: { operation: 'get' },
+  });
+  return (state?.pendingBlobs ?? []) as Array<Blob>;
+}
+
+// Must match `message::VERIFY_MESSAGE_TAG` of the identity contract
+const VERIFY_MESSAGE_TAG = 'hyle verify v3';
+
+// Builds the same message as `message::verify_message` of the identity contract, byte for byte:
+// the contract rejects signatures over any other message. `identity` is the identity key,
+// without the contract suffix.
+function verifyMessage(identity: string, nonce: number, blobs: Array<Blob>): string {
+  return `${VERIFY_MESSAGE_TAG}\ncontract: ${contract_name}\nnetwork: ${network_id}\nidentity: ${identity}\nnonce: ${nonce}\nblobs: 0x${blobsDigest(blobs)}`;
+}
+
+// Keccak256 of the borsh-encoded list of `(contract_name, data)` of the blobs, as
+// `message::blobs_digest`
+function blobsDigest(blobs: Array<Blob>): string {
+  const bytes: number[] = [...u32(blobs.length)];
+  for (const blob of blobs) {
+    const name = Array.from(Buffer.from(blob.contract_name, 'utf8'));
+    bytes.push(...u32(name.length), ...name, ...u32(blob.data.length), ...blob.data);
+  }
+  return Buffer.from(keccak_256(Uint8Array.from(bytes))).toString('hex');
+}
+
+// Little-endian u32, as borsh encodes lengths
+function u32(value: number): number[] {
+  return [value & 0xff, (value >> 8) & 0xff, (value >> 16) & 0xff, (value >>> 24) & 0xff];
+}
+
 //// Sign message using personal_sign
 async function signBlobs(blobs: Array<Blob>) {
   const { account, nonce } = await getAccount();
 
-  const message = `verify ${nonce} ${blobs.map((blob) => blob.contract_name + " [" + blob.data.join(", ") + "]").join(" ")}`;
+  const message = verifyMessage(account.replace(`.${contract_name}`, ''), nonce, blobs);
+  await setPendingBlobs(blobs);
 
   const hexMessage = toHexMessage(message); // Convert message to hex
 
@@ -185,7 +230,26 @@ export const onSignature: OnSignatureHandler = async ({
   }
 
   try {
-    const { blobs, nonce } = parseMessage(fromHexMessage(signature.data));
+    const { identity, nonce, network, digest } = parseMessage(fromHexMessage(signature.data));
+
+    // The message only holds the digest of the blobs, they are shown if they are the ones
+    // this snap was asked to sign
+    const pendingBlobs = await getPendingBlobs();
+    if (blobsDigest(pendingBlobs) !== digest) {
+      return {
+        content: (
+          <Box>
+            <Heading>Signature Data:</Heading>
+            <Text>Identity: {identity}</Text>
+            <Text>Nonce: {nonce}</Text>
+            <Text>Network: {network}</Text>
+            <Text>Unknown blobs with digest 0x{digest}</Text>
+          </Box>
+        ),
+        severity: SeverityLevel.Critical,
+      };
+    }
+    const blobs = pendingBlobs;
 
     const renderInsight = (blob: Blob) => {
       switch (blob.contract_name) {
@@ -209,7 +273,9 @@ export const onSignature: OnSignatureHandler = async ({
       content: (
         <Box>
           <Heading>Signature Data:</Heading>
-          <Text>Nonce: {nonce.toString()}</Text>
+          <Text>Identity: {identity}</Text>
+          <Text>Nonce: {nonce}</Text>
+          <Text>Network: {network}</Text>
           {blobs.map((blob, index) => (
             <Box key={`${blob.contract_name}-${index}`} >
               <Divider />
@@ -231,36 +297,38 @@ export const onSignature: OnSignatureHandler = async ({
   }
 };
 
-function parseMessage(message: string): { blobs: Blob[], nonce: number } {
-  const blobs: Blob[] = [];
-
-  const firstSpace = message.indexOf(' ');
-  const nonce = parseInt(message.slice(firstSpace + 1, message.indexOf(' ', firstSpace + 1)), 10);
-
-  // Retirer le nonce et le mot "verify" pour traiter le reste
-  const remaining = message.slice(firstSpace + 1 + nonce.toString().length).trim();
-
-  // Séparer les parties en fonction du nom du contrat suivi des crochets
-  const regex = /(\w+\d*)\s\[(.*?)\]/g;
-  let match;
-
-  while ((match = regex.exec(remaining)) !== null) {
-    const contractName = match[1] || ""; // Nom du contrat
-    const dataString = match[2] || ""; // Contenu du tableau sous forme de string
+// Parses a message built by `verifyMessage`
+function parseMessage(message: string): { identity: string, nonce: string, network: string, digest: string } {
+  const [tag, ...lines] = message.split('\n');
+  if (tag !== VERIFY_MESSAGE_TAG) {
+    throw new Error(`Unknown message ${tag}`);
+  }
 
-    // Convertir la chaîne des données en tableau de nombres
-    const data = dataString
-      .split(',') // Diviser par la virgule
-      .map(num => parseInt(num.trim(), 10)); // Convertir chaque élément en nombre
+  const fields = new Map<string, string>();
+  for (const line of lines) {
+    const separator = line.indexOf(': ');
+    if (separator === -1) {
+      throw new Error(`Invalid line ${line}`);
+    }
+    fields.set(line.slice(0, separator), line.slice(separator + 2));
+  }
 
-    // Ajouter le Blob avec le nonce
-    blobs.push({
-      contract_name: contractName, // Nom du contrat
-      data: data,
-    });
+  const field = (name: string): string => {
+    const value = fields.get(name);
+    if (value === undefined) {
+      throw new Error(`Missing ${name}`);
+    }
+    return value;
+  };
+  if (field('contract') !== contract_name) {
+    throw new Error(`Message for another contract ${field('contract')}`);
+  }
+  const blobs = field('blobs');
+  if (!blobs.startsWith('0x')) {
+    throw new Error(`Invalid blobs digest ${blobs}`);
   }
 
-  return { blobs, nonce };
+  return { identity: field('identity'), nonce: field('nonce'), network: field('network'), digest: blobs.slice(2) };
 }
 
 export const erc20ActionToInsight = (action: ERC20Action): string => {
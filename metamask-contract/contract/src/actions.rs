@@ -2,13 +2,161 @@ use borsh::{BorshDeserialize, BorshSerialize};
 use sdk::{Blob, BlobData, BlobIndex, ContractAction, ContractName};
 use serde::{Deserialize, Serialize};
 
+use std::collections::BTreeMap;
+
+use crate::admin::AdminCommand;
+use crate::message::Deadline;
+use crate::schemes::Scheme;
+
 extern crate alloc;
 
 /// Enum representing the actions that can be performed by the IdentityVerification contract.
 #[derive(Serialize, Deserialize, BorshSerialize, BorshDeserialize, Debug, Clone)]
 pub enum IdentityAction {
-    RegisterIdentity { signature: String },
-    VerifyIdentity { nonce: u128, signature: String },
+    RegisterIdentity {
+        signature: String,
+    },
+    VerifyIdentity {
+        nonce: u128,
+        signature: String,
+        /// Optional deadline, part of the signed message, after which the signature is
+        /// rejected
+        #[serde(default)]
+        valid_until: Option<Deadline>,
+    },
+    /// Same as `VerifyIdentity`, but the signature is an EIP-712 typed data signature
+    /// over `HyleTx { identity, nonce, validUntil, blobs }` (see [`crate::eip712`])
+    VerifyIdentityTyped {
+        nonce: u128,
+        signature: String,
+        /// Optional deadline, signed as `validUntil`, after which the signature is rejected
+        #[serde(default)]
+        valid_until: Option<Deadline>,
+    },
+    /// Same as `RegisterIdentity`, for an identity key of the given [`Scheme`]
+    RegisterIdentityWithScheme {
+        scheme: Scheme,
+        signature: String,
+    },
+    /// Same as `RegisterIdentity`, with a `personal_sign` signature over an EIP-4361
+    /// "Sign-In with Ethereum" `message` for the identity, listing
+    /// [`crate::siwe::registration_resource`] in its resources
+    RegisterIdentityWithSiwe {
+        message: String,
+        signature: String,
+    },
+    /// Moves the authority over the identity to `new_key`, keeping the identity and its
+    /// nonce. Both the current key (`signature`) and the new key (`new_key_signature`)
+    /// sign [`crate::message::rotation_message`].
+    RotateKey {
+        nonce: u128,
+        new_key: String,
+        signature: String,
+        new_key_signature: String,
+    },
+    /// Delegates `key`, an Ethereum address, to sign transactions for the identity until
+    /// block `expires_at`, for at most `max_uses` transactions only calling
+    /// `allowed_contracts`. Signed by the identity over
+    /// [`crate::message::session_key_message`].
+    RegisterSessionKey {
+        nonce: u128,
+        key: String,
+        expires_at: u64,
+        allowed_contracts: Vec<ContractName>,
+        max_uses: u32,
+        signature: String,
+    },
+    /// Same as `VerifyIdentity`, signed by a session key of the identity over
+    /// [`crate::message::session_verify_message`]
+    VerifyIdentityWithSessionKey {
+        nonce: u128,
+        key: String,
+        signature: String,
+        #[serde(default)]
+        valid_until: Option<Deadline>,
+    },
+    /// Permanently closes the identity: no further action is accepted for it, and it cannot
+    /// be registered again. Signed by the identity over [`crate::message::deletion_message`].
+    DeleteIdentity {
+        nonce: u128,
+        signature: String,
+    },
+    /// Lets `threshold` of `guardians`, other identities of the contract, recover the
+    /// identity after `delay` blocks. An empty list of guardians disables recovery. Signed by
+    /// the identity over [`crate::message::guardians_message`].
+    SetGuardians {
+        nonce: u128,
+        guardians: Vec<String>,
+        threshold: u32,
+        delay: u64,
+        signature: String,
+    },
+    /// Starts the recovery of the identity to `new_key`, with signatures of the guardians
+    /// over [`crate::message::recovery_message`], keyed by guardian
+    InitiateRecovery {
+        nonce: u128,
+        new_key: String,
+        signatures: BTreeMap<String, String>,
+    },
+    /// Cancels the pending recovery, signed by the identity over
+    /// [`crate::message::cancel_recovery_message`]
+    CancelRecovery {
+        nonce: u128,
+        signature: String,
+    },
+    /// Hands the identity over to the key of the pending recovery once its delay elapsed
+    CompleteRecovery,
+    /// Claims a unique human-readable handle resolving to the identity (see
+    /// [`crate::handles::validate_handle`]). Signed by the identity over
+    /// [`crate::message::claim_handle_message`].
+    ClaimHandle {
+        nonce: u128,
+        handle: String,
+        signature: String,
+    },
+    /// Moves the handle of the identity to the identity `to`, which must not hold one.
+    /// Signed by the identity over [`crate::message::transfer_handle_message`].
+    TransferHandle {
+        nonce: u128,
+        to: String,
+        signature: String,
+    },
+    /// Releases the handle of the identity, signed by the identity over
+    /// [`crate::message::release_handle_message`]
+    ReleaseHandle {
+        nonce: u128,
+        signature: String,
+    },
+    /// Sets the text record `key` of the identity (e.g. `display`, `avatar`, `com.github`),
+    /// or removes it when `value` is empty. Signed by the identity over
+    /// [`crate::message::record_message`].
+    SetRecord {
+        nonce: u128,
+        key: String,
+        value: String,
+        signature: String,
+    },
+    /// Registers an M-of-N identity controlled by registered Ethereum identities. The
+    /// identity key must be [`crate::MultisigInfo::id`] of the signers and threshold.
+    RegisterMultisig {
+        signers: Vec<String>,
+        threshold: u32,
+    },
+    /// Verifies a multisig identity with signatures of at least `threshold` of its signers
+    /// over [`crate::message::multisig_verify_message`], keyed by signer
+    VerifyMultisig {
+        nonce: u128,
+        signatures: BTreeMap<String, String>,
+        #[serde(default)]
+        valid_until: Option<Deadline>,
+    },
+    /// Changes the emergency controls of the contract, or upgrades its program. Sent by its admin
+    /// (see [`crate::admin::Admin`]) and signed over [`crate::message::admin_message`].
+    Admin {
+        nonce: u128,
+        command: AdminCommand,
+        signature: String,
+    },
 }
 
 impl IdentityAction {
@@ -0,0 +1,346 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use sdk::{
+    ContractName, HyleContract, OnchainEffect, ProgramId, RegisterContractEffect, StateCommitment,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::sync::{Mutex, PoisonError};
+
+use crate::{
+    actions::IdentityAction, active_identity, keys::IdentityKey, message, schemes::Scheme,
+    IdentityContractState,
+};
+
+/// Emergency controls of the contract, held by the admin identity set at its registration
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct Admin {
+    /// Identity key of the admin, a registered identity of the contract
+    pub identity: IdentityKey,
+    /// Rejects every registration
+    pub registrations_paused: bool,
+    /// Rejects every verification
+    pub verifications_paused: bool,
+    /// Identities whose actions are rejected, and whose approvals are not counted
+    pub frozen: BTreeSet<IdentityKey>,
+}
+
+impl Admin {
+    pub fn new(identity: &str) -> Self {
+        Admin {
+            identity: IdentityKey::new(identity),
+            registrations_paused: false,
+            verifications_paused: false,
+            frozen: BTreeSet::new(),
+        }
+    }
+
+    /// Checks that `identity` is a key some scheme registers identities with, written as
+    /// registered. The admin could not sign its commands otherwise.
+    pub fn validate_identity(identity: &str) -> Result<(), String> {
+        Scheme::ALL
+            .iter()
+            .any(|scheme| scheme.verifier().validate_key(identity).is_ok())
+            .then_some(())
+            .ok_or(format!("Invalid admin identity {identity}"))
+    }
+
+    /// Whether the identity `account` is frozen
+    pub fn is_frozen(&self, account: &str) -> bool {
+        self.frozen.contains(&IdentityKey::new(account))
+    }
+}
+
+/// A change of the emergency controls, sent by the admin
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum AdminCommand {
+    PauseRegistrations,
+    ResumeRegistrations,
+    PauseVerifications,
+    ResumeVerifications,
+    Freeze {
+        identity: String,
+    },
+    Unfreeze {
+        identity: String,
+    },
+    /// Re-registers the contract with the program `program_id` (hex), keeping its state
+    UpgradeProgram {
+        program_id: String,
+    },
+}
+
+impl fmt::Display for AdminCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdminCommand::PauseRegistrations => write!(f, "pause registrations"),
+            AdminCommand::ResumeRegistrations => write!(f, "resume registrations"),
+            AdminCommand::PauseVerifications => write!(f, "pause verifications"),
+            AdminCommand::ResumeVerifications => write!(f, "resume verifications"),
+            AdminCommand::Freeze { identity } => write!(f, "freeze {identity}"),
+            AdminCommand::Unfreeze { identity } => write!(f, "unfreeze {identity}"),
+            AdminCommand::UpgradeProgram { program_id } => {
+                write!(f, "upgrade program {program_id}")
+            }
+        }
+    }
+}
+
+impl IdentityContractState {
+    /// Admin of the contract and its emergency controls, if it has one
+    pub fn admin(&self) -> Option<&Admin> {
+        self.admin.as_ref()
+    }
+
+    /// Whether the admin froze the identity `account`
+    pub fn is_frozen(&self, account: &str) -> bool {
+        self.admin
+            .as_ref()
+            .is_some_and(|admin| admin.is_frozen(account))
+    }
+
+    /// Rejects `action` if the identity is frozen, or if the admin paused its kind of action
+    pub(crate) fn check_controls(
+        &self,
+        pub_key: &str,
+        action: &IdentityAction,
+    ) -> Result<(), String> {
+        let Some(admin) = &self.admin else {
+            return Ok(());
+        };
+        if self.is_frozen(pub_key) {
+            return Err(format!("Identity {pub_key} is frozen"));
+        }
+        let registration = matches!(
+            action,
+            IdentityAction::RegisterIdentity { .. }
+                | IdentityAction::RegisterIdentityWithScheme { .. }
+                | IdentityAction::RegisterIdentityWithSiwe { .. }
+                | IdentityAction::RegisterMultisig { .. }
+        );
+        if registration && admin.registrations_paused {
+            return Err("Registrations are paused".to_string());
+        }
+        let verification = matches!(
+            action,
+            IdentityAction::VerifyIdentity { .. }
+                | IdentityAction::VerifyIdentityTyped { .. }
+                | IdentityAction::VerifyIdentityWithSessionKey { .. }
+                | IdentityAction::VerifyMultisig { .. }
+        );
+        if verification && admin.verifications_paused {
+            return Err("Verifications are paused".to_string());
+        }
+        Ok(())
+    }
+
+    /// Applies `command`, signed by the admin
+    pub(crate) fn admin_command(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        command: AdminCommand,
+        contract_name: &ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        let admin = self.admin.as_mut().ok_or("The contract has no admin")?;
+        if admin.identity != IdentityKey::new(pub_key) {
+            return Err(format!("{pub_key} is not the admin"));
+        }
+
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+        let message =
+            message::admin_message(contract_name, &self.network_id, pub_key, &command, nonce);
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+
+        match &command {
+            AdminCommand::PauseRegistrations => admin.registrations_paused = true,
+            AdminCommand::ResumeRegistrations => admin.registrations_paused = false,
+            AdminCommand::PauseVerifications => admin.verifications_paused = true,
+            AdminCommand::ResumeVerifications => admin.verifications_paused = false,
+            AdminCommand::Freeze { identity } => {
+                if IdentityKey::new(identity) == admin.identity {
+                    return Err("The admin cannot freeze itself".to_string());
+                }
+                admin.frozen.insert(IdentityKey::new(identity));
+            }
+            AdminCommand::Unfreeze { identity } => {
+                admin.frozen.remove(&IdentityKey::new(identity));
+            }
+            // Applied by the node, from the effect returned along with the output
+            AdminCommand::UpgradeProgram { program_id } => {
+                parse_program_id(program_id)?;
+            }
+        }
+        stored_info.use_nonce(nonce);
+
+        Ok(format!("Admin command applied: {command}"))
+    }
+}
+
+/// Parses the hex of a program id, the 32 bytes image id of a risc0 program
+pub fn parse_program_id(program_id: &str) -> Result<ProgramId, String> {
+    match hex::decode(program_id) {
+        Ok(bytes) if bytes.len() == 32 => Ok(ProgramId(bytes)),
+        _ => Err(format!("Invalid program id {program_id}")),
+    }
+}
+
+/// Effect re-registering the contract `contract_name` with the program of an
+/// [`AdminCommand::UpgradeProgram`], starting from `state_commitment`
+pub(crate) fn upgrade_effect(
+    contract_name: &ContractName,
+    program_id: &str,
+    state_commitment: StateCommitment,
+) -> Result<OnchainEffect, String> {
+    Ok(OnchainEffect::RegisterContract(RegisterContractEffect {
+        verifier: "risc0".into(),
+        program_id: parse_program_id(program_id)?,
+        state_commitment,
+        contract_name: contract_name.clone(),
+    }))
+}
+
+/// Full states of the contracts upgraded by this process, by contract name. The upgrade
+/// re-registers the contract with the Merkle root of its state, which an indexer cannot
+/// rebuild the full state from (see `TryFrom<StateCommitment>`): it gets back the state it
+/// indexed instead.
+static UPGRADED_STATES: Mutex<BTreeMap<String, IdentityContractState>> =
+    Mutex::new(BTreeMap::new());
+
+/// Keeps the full state of `contract_name` after it executed an upgrade
+pub(crate) fn keep_upgraded_state(contract_name: &ContractName, state: &IdentityContractState) {
+    UPGRADED_STATES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(contract_name.0.clone(), state.clone());
+}
+
+/// The full state kept after an upgrade re-registering its contract with `commitment`
+pub(crate) fn upgraded_state(commitment: &StateCommitment) -> Option<IdentityContractState> {
+    UPGRADED_STATES
+        .lock()
+        .unwrap_or_else(PoisonError::into_inner)
+        .values()
+        .find(|state| state.commit() == *commitment)
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commitment::IdentityContractRoot;
+    use crate::tests::{contract_name, personal_sign, register, signing_key, verify};
+    use crate::DEFAULT_NETWORK_ID;
+    use sdk::ContractInput;
+
+    #[test]
+    fn indexers_keep_the_state_across_upgrades() {
+        let admin_key = signing_key(1);
+        let admin = crate::tests::address(&admin_key);
+        let mut state = IdentityContractState::new(
+            DEFAULT_NETWORK_ID.to_string(),
+            crate::LegacyMessages::Rejected,
+            crate::DEFAULT_SIWE_CHAIN_ID,
+            Some(admin.clone()),
+        );
+        register(&mut state, &admin_key);
+        let user_key = signing_key(2);
+        let user = register(&mut state, &user_key);
+        verify(&mut state, &user, &user_key, 0).unwrap();
+        let mut root: IdentityContractRoot = state.root();
+
+        let command = AdminCommand::UpgradeProgram {
+            program_id: "ab".repeat(32),
+        };
+        let message =
+            message::admin_message(&contract_name(), DEFAULT_NETWORK_ID, &admin, &command, 0);
+        let action = IdentityAction::Admin {
+            nonce: 0,
+            command,
+            signature: personal_sign(&admin_key, &message),
+        };
+        let mut input = ContractInput {
+            identity: format!("{admin}.{}", contract_name()).into(),
+            blobs: vec![action.as_blob(contract_name())],
+            ..Default::default()
+        };
+        input.private_input = borsh::to_vec(&state.witness(&input).unwrap()).unwrap();
+
+        // The guest proves the upgrade, and the indexer executes it on the full state
+        let (_, _, effects) = root.execute(&input).unwrap();
+        state.execute(&input).unwrap();
+        let [OnchainEffect::RegisterContract(effect)] = effects.as_slice() else {
+            panic!("The upgrade did not re-register the contract");
+        };
+        assert_eq!(effect.state_commitment, state.commit());
+
+        // Then rebuilds its state from the re-registration
+        let mut indexed = IdentityContractState::try_from(effect.state_commitment.clone()).unwrap();
+        assert_eq!(indexed.commit(), state.commit());
+        verify(&mut indexed, &user, &user_key, 1).unwrap();
+
+        // Only from the commitments of upgrades
+        assert!(IdentityContractState::try_from(indexed.commit()).is_err());
+    }
+
+    #[test]
+    fn approvals_of_frozen_identities_are_not_counted() {
+        let admin_key = signing_key(1);
+        let admin = crate::tests::address(&admin_key);
+        let mut state = IdentityContractState::new(
+            DEFAULT_NETWORK_ID.to_string(),
+            crate::LegacyMessages::Rejected,
+            crate::DEFAULT_SIWE_CHAIN_ID,
+            Some(admin.clone()),
+        );
+        register(&mut state, &admin_key);
+        let keys: Vec<_> = (2..5).map(signing_key).collect();
+        let signers: Vec<String> = keys.iter().map(|key| register(&mut state, key)).collect();
+
+        let command = AdminCommand::Freeze {
+            identity: signers[0].clone(),
+        };
+        let message =
+            message::admin_message(&contract_name(), DEFAULT_NETWORK_ID, &admin, &command, 0);
+        let action = IdentityAction::Admin {
+            nonce: 0,
+            command,
+            signature: personal_sign(&admin_key, &message),
+        };
+        crate::tests::run(&mut state, &admin, action, &[], None).unwrap();
+
+        let multisig = crate::MultisigInfo::id(&signers, 2);
+        let action = IdentityAction::RegisterMultisig {
+            signers: signers.clone(),
+            threshold: 2,
+        };
+        crate::tests::run(&mut state, &multisig, action, &[], None).unwrap();
+        let message = message::multisig_verify_message(
+            &contract_name(),
+            DEFAULT_NETWORK_ID,
+            &multisig,
+            0,
+            None,
+            &[],
+        );
+        let approve = |approvers: &[usize]| IdentityAction::VerifyMultisig {
+            nonce: 0,
+            signatures: approvers
+                .iter()
+                .map(|&i| (signers[i].clone(), personal_sign(&keys[i], &message)))
+                .collect(),
+            valid_until: None,
+        };
+
+        assert_eq!(
+            crate::tests::run(&mut state, &multisig, approve(&[0, 1]), &[], None),
+            Err("Error verifying identity: 1 approvals out of the 2 required".to_string())
+        );
+        crate::tests::run(&mut state, &multisig, approve(&[0, 1, 2]), &[], None).unwrap();
+    }
+}
@@ -0,0 +1,483 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use sdk::{utils::parse_raw_contract_input, ContractInput, HyleContract, OnchainEffect, RunResult};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use crate::{
+    account_key,
+    actions::IdentityAction,
+    admin::Admin,
+    keys::IdentityKey,
+    merkle::{self, Hash, EMPTY},
+    migration::{VersionedLeaf, VersionedState},
+    recovery::RecoveryConfig,
+    AccountInfo, IdentityContractState, LegacyMessages, MultisigInfo, SessionKey,
+};
+
+/// Everything the state holds about an identity key, committed as a single leaf (see
+/// [`VersionedLeaf`])
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Default, Eq, PartialEq,
+)]
+pub struct AccountLeaf {
+    pub info: Option<AccountInfo>,
+    pub multisig: Option<MultisigInfo>,
+    pub session_keys: BTreeMap<IdentityKey, SessionKey>,
+    pub recovery: Option<RecoveryConfig>,
+    pub records: BTreeMap<String, String>,
+}
+
+/// Key of a leaf of the state tree, hashed into its path
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LeafKey {
+    Account(IdentityKey),
+    Handle(String),
+}
+
+impl LeafKey {
+    fn path(&self) -> Hash {
+        merkle::path(&borsh::to_vec(self).expect("Failed to encode leaf key"))
+    }
+
+    fn hash(&self, value: Option<impl BorshSerialize>) -> Hash {
+        match value {
+            Some(value) => merkle::leaf_hash(
+                &self.path(),
+                &borsh::to_vec(&value).expect("Failed to encode leaf"),
+            ),
+            None => EMPTY,
+        }
+    }
+}
+
+impl fmt::Display for LeafKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LeafKey::Account(key) => write!(f, "account {key}"),
+            LeafKey::Handle(handle) => write!(f, "handle {handle}"),
+        }
+    }
+}
+
+/// The leaves of the state read or written by a transaction, with the Merkle proof of their
+/// paths. Sent by the prover in `ContractInput::private_input`.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Witness {
+    /// Leaves of the identity keys as committed, `None` when the state holds nothing about
+    /// them
+    pub accounts: Vec<(IdentityKey, Option<VersionedLeaf>)>,
+    /// Owners of the handles, `None` for unclaimed handles
+    pub handles: Vec<(String, Option<IdentityKey>)>,
+    pub proof: Vec<Option<Hash>>,
+}
+
+/// The state of the contract as committed on-chain (see [`crate::migration::VersionedState`]): the root of a
+/// sparse Merkle tree of the accounts and handles, and the configuration. Transactions
+/// are executed against the leaves of a [`Witness`], checked against the root.
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone)]
+pub struct IdentityContractRoot {
+    pub root: Hash,
+    pub network_id: String,
+    pub legacy_messages: LegacyMessages,
+    pub siwe_chain_id: u64,
+    pub admin: Option<Admin>,
+}
+
+impl IdentityContractRoot {
+    pub fn execute(&mut self, input: &ContractInput) -> RunResult {
+        let witness: Witness =
+            borsh::from_slice(&input.private_input).map_err(|_| "Invalid state witness")?;
+
+        let mut state = IdentityContractState::new(
+            self.network_id.clone(),
+            self.legacy_messages,
+            self.siwe_chain_id,
+            None,
+        );
+        state.admin = self.admin.clone();
+        let mut witnessed = BTreeSet::new();
+        for (key, leaf) in &witness.accounts {
+            witnessed.insert(LeafKey::Account(key.clone()));
+            if let Some(leaf) = leaf {
+                state.insert_account_leaf(key.clone(), leaf.clone().migrate());
+            }
+        }
+        for (handle, owner) in &witness.handles {
+            witnessed.insert(LeafKey::Handle(handle.clone()));
+            if let Some(owner) = owner {
+                state.handles.insert(handle.clone(), owner.clone());
+            }
+        }
+        // Leaves left out of the witness would be taken as absent
+        let missing = state
+            .touched_leaves(input)?
+            .into_iter()
+            .find(|key| !witnessed.contains(key));
+        if let Some(key) = missing {
+            return Err(format!("State witness is missing the leaf of {key}"));
+        }
+
+        let initial_root = merkle::root_from_proof(&witnessed_leaves(&witness), &witness.proof)?;
+        if initial_root != self.root {
+            return Err("State witness does not match the committed root".to_string());
+        }
+
+        let (output, ctx, mut effects) = state.execute(input)?;
+        self.root = merkle::root_from_proof(&witness_leaves(&state, &witness), &witness.proof)?;
+        self.legacy_messages = state.legacy_messages;
+        self.admin = state.admin;
+
+        // The partial state only commits to the leaves of the witness
+        let commitment = VersionedState::V3(self.clone()).commit();
+        for effect in &mut effects {
+            if let OnchainEffect::RegisterContract(effect) = effect {
+                effect.state_commitment = commitment.clone();
+            }
+        }
+        Ok((output, ctx, effects))
+    }
+}
+
+/// `(path, hash)` of the leaves of the witness, as committed
+fn witnessed_leaves(witness: &Witness) -> Vec<(Hash, Hash)> {
+    let accounts = witness.accounts.iter().map(|(key, leaf)| {
+        let leaf_key = LeafKey::Account(key.clone());
+        (leaf_key.path(), leaf_key.hash(leaf.as_ref()))
+    });
+    let handles = witness.handles.iter().map(|(handle, owner)| {
+        let leaf_key = LeafKey::Handle(handle.clone());
+        (leaf_key.path(), leaf_key.hash(owner.as_ref()))
+    });
+    accounts.chain(handles).collect()
+}
+
+/// `(path, hash)` of the leaves of the witness, as currently held by `state`
+fn witness_leaves(state: &IdentityContractState, witness: &Witness) -> Vec<(Hash, Hash)> {
+    let accounts = witness.accounts.iter().map(|(key, _)| {
+        let leaf_key = LeafKey::Account(key.clone());
+        (leaf_key.path(), leaf_key.hash(state.versioned_leaf(key)))
+    });
+    let handles = witness.handles.iter().map(|(handle, _)| {
+        let leaf_key = LeafKey::Handle(handle.clone());
+        (leaf_key.path(), leaf_key.hash(state.handles.get(handle)))
+    });
+    accounts.chain(handles).collect()
+}
+
+impl IdentityContractState {
+    /// The on-chain commitment of this state
+    pub fn root(&self) -> IdentityContractRoot {
+        IdentityContractRoot {
+            root: merkle::root(&self.leaves()),
+            network_id: self.network_id.clone(),
+            legacy_messages: self.legacy_messages,
+            siwe_chain_id: self.siwe_chain_id,
+            admin: self.admin.clone(),
+        }
+    }
+
+    /// Witness of the leaves read or written by the transaction of `input`, to send along
+    /// with it to the zkvm
+    pub fn witness(&self, input: &ContractInput) -> Result<Witness, String> {
+        let mut keys: Vec<LeafKey> = self.touched_leaves(input)?.into_iter().collect();
+        keys.sort_by_key(LeafKey::path);
+        let paths: Vec<Hash> = keys.iter().map(LeafKey::path).collect();
+
+        let mut witness = Witness {
+            proof: merkle::prove(&self.leaves(), &paths),
+            ..Witness::default()
+        };
+        for key in keys {
+            match key {
+                LeafKey::Account(key) => {
+                    let leaf = self.versioned_leaf(&key);
+                    witness.accounts.push((key, leaf));
+                }
+                LeafKey::Handle(handle) => {
+                    let owner = self.handles.get(&handle).cloned();
+                    witness.handles.push((handle, owner));
+                }
+            }
+        }
+        Ok(witness)
+    }
+
+    /// `(path, hash)` of all the leaves of the state, sorted by path
+    fn leaves(&self) -> Vec<(Hash, Hash)> {
+        let accounts: BTreeSet<&IdentityKey> = self
+            .identities
+            .keys()
+            .chain(self.multisigs.keys())
+            .chain(self.session_keys.keys())
+            .chain(self.recoveries.keys())
+            .chain(self.records.keys())
+            .collect();
+
+        let mut leaves: Vec<(Hash, Hash)> = accounts
+            .into_iter()
+            .map(|key| {
+                let leaf_key = LeafKey::Account(key.clone());
+                (leaf_key.path(), leaf_key.hash(self.versioned_leaf(key)))
+            })
+            .chain(self.handles.iter().map(|(handle, owner)| {
+                let leaf_key = LeafKey::Handle(handle.clone());
+                (leaf_key.path(), leaf_key.hash(Some(owner)))
+            }))
+            .filter(|(_, hash)| hash != &EMPTY)
+            .collect();
+        leaves.sort();
+        leaves
+    }
+
+    fn account_leaf(&self, key: &IdentityKey) -> Option<AccountLeaf> {
+        let leaf = AccountLeaf {
+            info: self.identities.get(key).cloned(),
+            multisig: self.multisigs.get(key).cloned(),
+            session_keys: self.session_keys.get(key).cloned().unwrap_or_default(),
+            recovery: self.recoveries.get(key).cloned(),
+            records: self.records.get(key).cloned().unwrap_or_default(),
+        };
+        (leaf != AccountLeaf::default()).then_some(leaf)
+    }
+
+    /// The leaf of `key`, in the encoding written by this program
+    fn versioned_leaf(&self, key: &IdentityKey) -> Option<VersionedLeaf> {
+        self.account_leaf(key).map(VersionedLeaf::V3)
+    }
+
+    fn insert_account_leaf(&mut self, key: IdentityKey, leaf: AccountLeaf) {
+        if let Some(info) = leaf.info {
+            self.identities.insert(key.clone(), info);
+        }
+        if let Some(multisig) = leaf.multisig {
+            self.multisigs.insert(key.clone(), multisig);
+        }
+        if !leaf.session_keys.is_empty() {
+            self.session_keys.insert(key.clone(), leaf.session_keys);
+        }
+        if let Some(recovery) = leaf.recovery {
+            self.recoveries.insert(key.clone(), recovery);
+        }
+        if !leaf.records.is_empty() {
+            self.records.insert(key, leaf.records);
+        }
+    }
+
+    /// Leaves the transaction of `input` may read or write: the leaf of the identity, and
+    /// those of the other identities and handles named by its action
+    fn touched_leaves(&self, input: &ContractInput) -> Result<BTreeSet<LeafKey>, String> {
+        let (action, _) = parse_raw_contract_input::<IdentityAction>(input)?;
+        let contract_name = &input
+            .blobs
+            .get(input.index.0)
+            .ok_or("No blob")?
+            .contract_name;
+        let pub_key = account_key(&input.identity, contract_name)?;
+
+        let account = |key: &str| LeafKey::Account(IdentityKey::new(key));
+        let mut leaves = BTreeSet::from([account(pub_key)]);
+        let current_handle = self
+            .handle_of(pub_key)
+            .map(|handle| LeafKey::Handle(handle.to_string()));
+        match action {
+            IdentityAction::RegisterMultisig { signers, .. } => {
+                leaves.extend(signers.iter().map(|signer| account(signer)));
+            }
+            IdentityAction::VerifyMultisig { signatures, .. }
+            | IdentityAction::InitiateRecovery { signatures, .. } => {
+                leaves.extend(signatures.keys().map(|signer| account(signer)));
+            }
+            IdentityAction::SetGuardians { guardians, .. } => {
+                leaves.extend(guardians.iter().map(|guardian| account(guardian)));
+            }
+            IdentityAction::ClaimHandle { handle, .. } => {
+                leaves.insert(LeafKey::Handle(handle));
+            }
+            IdentityAction::TransferHandle { to, .. } => {
+                leaves.insert(account(&to));
+                leaves.extend(current_handle);
+            }
+            IdentityAction::ReleaseHandle { .. } | IdentityAction::DeleteIdentity { .. } => {
+                leaves.extend(current_handle);
+            }
+            _ => {}
+        }
+        Ok(leaves)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schemes::Scheme;
+
+    const SIGNERS: [&str; 2] = [
+        "0x1111111111111111111111111111111111111111",
+        "0x2222222222222222222222222222222222222222",
+    ];
+
+    /// A state holding the signers, and the input registering their multisig
+    fn register_multisig() -> (IdentityContractState, ContractInput) {
+        let mut state = IdentityContractState::default();
+        for signer in SIGNERS {
+            state.identities.insert(
+                IdentityKey::new(signer),
+                AccountInfo {
+                    public_key: None,
+                    nonce: 0,
+                    lanes: BTreeMap::new(),
+                    scheme: Scheme::Ethereum,
+                    authority: None,
+                    deleted: false,
+                    handle: None,
+                },
+            );
+        }
+
+        let signers: Vec<String> = SIGNERS.iter().map(|signer| signer.to_string()).collect();
+        let id = MultisigInfo::id(&signers, 2);
+        let action = IdentityAction::RegisterMultisig {
+            signers,
+            threshold: 2,
+        };
+        let input = ContractInput {
+            identity: format!("{id}.mmid").into(),
+            blobs: vec![action.as_blob("mmid".into())],
+            ..Default::default()
+        };
+        (state, input)
+    }
+
+    fn with_witness(input: &ContractInput, witness: &Witness) -> ContractInput {
+        ContractInput {
+            private_input: borsh::to_vec(witness).unwrap(),
+            ..input.clone()
+        }
+    }
+
+    #[test]
+    fn executes_against_the_witness() {
+        let (mut state, input) = register_multisig();
+        let mut root = state.root();
+        let input = with_witness(&input, &state.witness(&input).unwrap());
+
+        root.execute(&input).unwrap();
+        state.execute(&input).unwrap();
+        assert_eq!(root.root, state.root().root);
+    }
+
+    #[test]
+    fn rejects_forged_witnesses() {
+        let (state, input) = register_multisig();
+        let witness = state.witness(&input).unwrap();
+        let signer = IdentityKey::new(SIGNERS[0]);
+        let forge = |forge_leaf: &dyn Fn(&mut Option<VersionedLeaf>)| {
+            let mut witness = witness.clone();
+            for (key, leaf) in &mut witness.accounts {
+                if *key == signer {
+                    forge_leaf(leaf);
+                }
+            }
+            state.root().execute(&with_witness(&input, &witness))
+        };
+
+        // A leaf holding other values
+        let result = forge(&|leaf| {
+            if let Some(VersionedLeaf::V3(AccountLeaf {
+                info: Some(info), ..
+            })) = leaf
+            {
+                info.scheme = Scheme::Ed25519;
+            }
+        });
+        assert_eq!(
+            result.map(|(output, ..)| output),
+            Err("State witness does not match the committed root".to_string())
+        );
+
+        // A present leaf claimed absent
+        let result = forge(&|leaf| *leaf = None);
+        assert_eq!(
+            result.map(|(output, ..)| output),
+            Err("State witness does not match the committed root".to_string())
+        );
+
+        // A leaf left out of the witness
+        let mut partial = witness.clone();
+        partial.accounts.retain(|(key, _)| *key != signer);
+        assert_eq!(
+            state
+                .root()
+                .execute(&with_witness(&input, &partial))
+                .map(|(output, ..)| output),
+            Err(format!(
+                "State witness is missing the leaf of account {signer}"
+            ))
+        );
+
+        // A proof of other leaves
+        let mut wrong_proof = witness.clone();
+        wrong_proof.proof = merkle::prove(&state.leaves(), &[merkle::path(b"other")]);
+        assert!(state
+            .root()
+            .execute(&with_witness(&input, &wrong_proof))
+            .is_err());
+    }
+
+    #[test]
+    fn migrates_legacy_leaves() {
+        let (mut state, input) = register_multisig();
+        let witness = state.witness(&input).unwrap();
+
+        // The signers were registered by a program writing unversioned leaves
+        let legacy = |leaf: &VersionedLeaf| {
+            let VersionedLeaf::V3(AccountLeaf {
+                info: Some(info), ..
+            }) = leaf
+            else {
+                return leaf.clone();
+            };
+            VersionedLeaf::V2(crate::migration::AccountLeafV2 {
+                info: Some(crate::migration::AccountInfoV2 {
+                    public_key: info.public_key,
+                    nonce: info.nonce,
+                    lanes: info.lanes.clone(),
+                    scheme: info.scheme,
+                    authority: info.authority.as_ref().map(IdentityKey::to_string),
+                    deleted: info.deleted,
+                    handle: info.handle.clone(),
+                }),
+                multisig: None,
+                session_keys: BTreeMap::new(),
+                recovery: None,
+                records: BTreeMap::new(),
+            })
+        };
+        let mut legacy_witness = witness.clone();
+        for (_, leaf) in &mut legacy_witness.accounts {
+            *leaf = leaf.as_ref().map(legacy);
+        }
+        let legacy_leaves: Vec<(Hash, Hash)> = {
+            let mut leaves = witnessed_leaves(&legacy_witness);
+            leaves.retain(|(_, hash)| hash != &EMPTY);
+            leaves.sort();
+            leaves
+        };
+        let mut root = state.root();
+        root.root = merkle::root(&legacy_leaves);
+        assert_ne!(root.root, state.root().root);
+
+        // The leaves are checked in the encoding they were committed with
+        assert!(root
+            .clone()
+            .execute(&with_witness(&input, &witness))
+            .is_err());
+
+        // And written back in the current one
+        root.execute(&with_witness(&input, &legacy_witness))
+            .unwrap();
+        state.execute(&input).unwrap();
+        assert_eq!(root.root, state.root().root);
+    }
+}
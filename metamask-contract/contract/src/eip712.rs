@@ -0,0 +1,193 @@
+use sdk::{Blob, ContractName};
+
+use crate::message::Deadline;
+use serde_json::json;
+use sha3::{Digest, Keccak256};
+
+/// Version of the EIP-712 domain used by the identity contract
+pub const DOMAIN_VERSION: &str = "2";
+
+const DOMAIN_TYPE: &str = "EIP712Domain(string name,string version,bytes32 salt)";
+const HYLE_TX_TYPE: &str = "HyleTx(string identity,uint256 nonce,string validUntil,Blob[] blobs)Blob(string contract_name,bytes data)";
+const BLOB_TYPE: &str = "Blob(string contract_name,bytes data)";
+
+/// Computes the EIP-712 digest of a `HyleTx { identity, nonce, validUntil, blobs }` message,
+/// as signed by `eth_signTypedData_v4`. The contract name is used as the domain name and the
+/// keccak256 of the network id as the domain salt. Like the verify message, it names the
+/// identity key it authorizes a transaction of.
+pub fn hyle_tx_digest(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    nonce: u128,
+    valid_until: Option<Deadline>,
+    blobs: &[Blob],
+) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update([0x19, 0x01]);
+    hasher.update(domain_separator(contract_name, network_id));
+    hasher.update(hash_hyle_tx(identity, nonce, valid_until, blobs));
+    hasher.finalize().into()
+}
+
+/// Builds the typed data expected by `eth_signTypedData_v4` for the given transaction.
+pub fn hyle_tx_typed_data(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    nonce: u128,
+    valid_until: Option<Deadline>,
+    blobs: &[Blob],
+) -> serde_json::Value {
+    json!({
+        "types": {
+            "EIP712Domain": [
+                { "name": "name", "type": "string" },
+                { "name": "version", "type": "string" },
+                { "name": "salt", "type": "bytes32" },
+            ],
+            "HyleTx": [
+                { "name": "identity", "type": "string" },
+                { "name": "nonce", "type": "uint256" },
+                { "name": "validUntil", "type": "string" },
+                { "name": "blobs", "type": "Blob[]" },
+            ],
+            "Blob": [
+                { "name": "contract_name", "type": "string" },
+                { "name": "data", "type": "bytes" },
+            ],
+        },
+        "primaryType": "HyleTx",
+        "domain": {
+            "name": contract_name.0,
+            "version": DOMAIN_VERSION,
+            "salt": format!("0x{}", hex::encode(network_salt(network_id))),
+        },
+        "message": {
+            "identity": identity,
+            "nonce": nonce.to_string(),
+            "validUntil": valid_until_field(valid_until),
+            "blobs": blobs
+                .iter()
+                .map(|blob| json!({
+                    "contract_name": blob.contract_name.0,
+                    "data": format!("0x{}", hex::encode(&blob.data.0)),
+                }))
+                .collect::<Vec<_>>(),
+        },
+    })
+}
+
+fn domain_separator(contract_name: &ContractName, network_id: &str) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(Keccak256::digest(DOMAIN_TYPE));
+    hasher.update(Keccak256::digest(contract_name.0.as_bytes()));
+    hasher.update(Keccak256::digest(DOMAIN_VERSION));
+    hasher.update(network_salt(network_id));
+    hasher.finalize().into()
+}
+
+fn network_salt(network_id: &str) -> [u8; 32] {
+    Keccak256::digest(network_id.as_bytes()).into()
+}
+
+/// `validUntil` of a transaction: its deadline as in the verify message (e.g. `block 1200`),
+/// empty without one
+fn valid_until_field(valid_until: Option<Deadline>) -> String {
+    valid_until
+        .map(|deadline| deadline.to_string())
+        .unwrap_or_default()
+}
+
+fn hash_hyle_tx(
+    identity: &str,
+    nonce: u128,
+    valid_until: Option<Deadline>,
+    blobs: &[Blob],
+) -> [u8; 32] {
+    let mut blobs_hasher = Keccak256::new();
+    for blob in blobs {
+        blobs_hasher.update(hash_blob(blob));
+    }
+
+    let mut hasher = Keccak256::new();
+    hasher.update(Keccak256::digest(HYLE_TX_TYPE));
+    hasher.update(Keccak256::digest(identity.as_bytes()));
+    hasher.update(encode_uint256(nonce));
+    hasher.update(Keccak256::digest(valid_until_field(valid_until)));
+    hasher.update(blobs_hasher.finalize());
+    hasher.finalize().into()
+}
+
+fn hash_blob(blob: &Blob) -> [u8; 32] {
+    let mut hasher = Keccak256::new();
+    hasher.update(Keccak256::digest(BLOB_TYPE));
+    hasher.update(Keccak256::digest(blob.contract_name.0.as_bytes()));
+    hasher.update(Keccak256::digest(&blob.data.0));
+    hasher.finalize().into()
+}
+
+fn encode_uint256(value: u128) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[16..].copy_from_slice(&value.to_be_bytes());
+    word
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sdk::BlobData;
+
+    const IDENTITY: &str = "0x437aa724e898f0ba345852bbbc2e416d9346e1c9";
+
+    fn blobs() -> Vec<Blob> {
+        vec![
+            Blob {
+                contract_name: "hyllar".into(),
+                data: BlobData(vec![1, 2, 3]),
+            },
+            Blob {
+                contract_name: "hydentity".into(),
+                data: BlobData(vec![]),
+            },
+        ]
+    }
+
+    /// Digests of the typed data of `hyle_tx_typed_data`, as encoded by the reference
+    /// `eth_signTypedData_v4` implementation of alloy (`TypedData::eip712_signing_hash`)
+    #[test]
+    fn matches_the_reference_encoder() {
+        let vectors = [
+            (
+                None,
+                "25b8631e187cea1e3a2ba9d8e15e60b721844e8cf4ebcfa12a7579bb90de2e14",
+            ),
+            (
+                Some(Deadline::BlockHeight(1200)),
+                "db82084c89b21d3635f08857465cbee605aa2266550d7f4aa0da1e4d2f448c72",
+            ),
+        ];
+        for (valid_until, digest) in vectors {
+            let typed_data = hyle_tx_typed_data(
+                &"mmid".into(),
+                "hyle-devnet",
+                IDENTITY,
+                7,
+                valid_until,
+                &blobs(),
+            );
+            assert_eq!(typed_data["message"]["identity"], IDENTITY);
+            assert_eq!(
+                hex::encode(hyle_tx_digest(
+                    &"mmid".into(),
+                    "hyle-devnet",
+                    IDENTITY,
+                    7,
+                    valid_until,
+                    &blobs()
+                )),
+                digest
+            );
+        }
+    }
+}
@@ -0,0 +1,150 @@
+use sdk::ContractName;
+
+use crate::{active_identity, keys::IdentityKey, message, IdentityContractState};
+
+/// Checks that `handle` is 3 to 32 lowercase letters, digits or dashes, starting with a
+/// letter and not ending with a dash, so that it cannot be mistaken for an identity key
+pub fn validate_handle(handle: &str) -> Result<(), String> {
+    let valid = (3..=32).contains(&handle.len())
+        && handle.starts_with(|c: char| c.is_ascii_lowercase())
+        && !handle.ends_with('-')
+        && !handle.starts_with("multisig-")
+        && handle
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+    if !valid {
+        return Err(format!("Invalid handle {handle}"));
+    }
+    Ok(())
+}
+
+impl IdentityContractState {
+    /// Identity key the handle resolves to
+    pub fn resolve_handle(&self, handle: &str) -> Option<&IdentityKey> {
+        self.handles.get(handle)
+    }
+
+    /// Handle claimed by the identity `account`
+    pub fn handle_of(&self, account: &str) -> Option<&str> {
+        self.identities
+            .get(&IdentityKey::new(account))?
+            .handle
+            .as_deref()
+    }
+
+    pub(crate) fn claim_handle(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        handle: String,
+        contract_name: &ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        validate_handle(&handle)?;
+        if let Some(owner) = self.handles.get(&handle) {
+            return Err(format!("Handle {handle} is already claimed by {owner}"));
+        }
+
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+        if let Some(current) = &stored_info.handle {
+            return Err(format!("{pub_key} already holds the handle {current}"));
+        }
+
+        let message =
+            message::claim_handle_message(contract_name, &self.network_id, pub_key, &handle, nonce);
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+
+        stored_info.use_nonce(nonce);
+        stored_info.handle = Some(handle.clone());
+        self.handles
+            .insert(handle.clone(), IdentityKey::new(pub_key));
+
+        Ok(format!("Handle {handle} claimed by {pub_key}"))
+    }
+
+    /// Moves the handle of the identity to `to`, a registered identity without a handle
+    pub(crate) fn transfer_handle(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        to: &str,
+        contract_name: &ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        let recipient = IdentityKey::new(to);
+        match self.identities.get(&recipient) {
+            Some(info) if info.deleted => return Err(format!("{to} was deleted")),
+            Some(info) if info.handle.is_some() => {
+                return Err(format!("{to} already holds a handle"))
+            }
+            Some(_) => {}
+            None => return Err(format!("{to} is not registered")),
+        }
+
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+        let handle = stored_info
+            .handle
+            .clone()
+            .ok_or(format!("{pub_key} holds no handle"))?;
+
+        let message = message::transfer_handle_message(
+            contract_name,
+            &self.network_id,
+            pub_key,
+            &handle,
+            to,
+            nonce,
+        );
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+
+        stored_info.use_nonce(nonce);
+        stored_info.handle = None;
+        if let Some(recipient_info) = self.identities.get_mut(&recipient) {
+            recipient_info.handle = Some(handle.clone());
+        }
+        self.handles.insert(handle.clone(), recipient);
+
+        Ok(format!("Handle {handle} transferred to {to}"))
+    }
+
+    pub(crate) fn release_handle(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        contract_name: &ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+        let handle = stored_info
+            .handle
+            .clone()
+            .ok_or(format!("{pub_key} holds no handle"))?;
+
+        let message = message::release_handle_message(
+            contract_name,
+            &self.network_id,
+            pub_key,
+            &handle,
+            nonce,
+        );
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+
+        stored_info.use_nonce(nonce);
+        stored_info.handle = None;
+        self.handles.remove(&handle);
+
+        Ok(format!("Handle {handle} released"))
+    }
+}
@@ -18,6 +18,7 @@ use client_sdk::contract_indexer::{
 };
 use sdk::Identity;
 use serde::Serialize;
+use std::collections::BTreeMap;
 
 use client_sdk::contract_indexer::axum;
 impl ContractHandler for IdentityContractState {
@@ -25,6 +26,10 @@ impl ContractHandler for IdentityContractState {
         let (router, api) = OpenApiRouter::default()
             .routes(routes!(get_state))
             .routes(routes!(get_nonce))
+            .routes(routes!(get_lane_nonce))
+            .routes(routes!(resolve_handle))
+            .routes(routes!(get_handle))
+            .routes(routes!(get_records))
             .split_for_parts();
 
         (router.with_state(store), api)
@@ -76,12 +81,149 @@ pub async fn get_nonce(
         anyhow!("Contract '{}' not found", store.contract_name),
     ))?;
 
-    let info = state
-        .get_identity_info(&account.0)
+    let nonce = state
+        .get_nonce(&account.0)
         .map_err(|err| AppError(StatusCode::NOT_FOUND, anyhow::anyhow!(err)))?;
 
     Ok(Json(NonceResponse {
         account: account.0,
-        nonce: info.nonce,
+        nonce,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/nonce/{account}/{lane}",
+    params(
+        ("account" = String, Path, description = "Account"),
+        ("lane" = u64, Path, description = "Nonce lane")
+    ),
+    tag = "Contract",
+    responses(
+        (status = OK, description = "Get next nonce of account in a nonce lane", body = NonceResponse)
+    )
+)]
+pub async fn get_lane_nonce(
+    Path((account, lane)): Path<(Identity, u64)>,
+    State(state): State<ContractHandlerStore<IdentityContractState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let state = store.state.clone().ok_or(AppError(
+        StatusCode::NOT_FOUND,
+        anyhow!("Contract '{}' not found", store.contract_name),
+    ))?;
+
+    let nonce = state
+        .get_lane_nonce(&account.0, lane)
+        .map_err(|err| AppError(StatusCode::NOT_FOUND, anyhow::anyhow!(err)))?;
+
+    Ok(Json(NonceResponse {
+        account: account.0,
+        nonce,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct HandleResponse {
+    handle: String,
+    account: String,
+}
+
+#[utoipa::path(
+    get,
+    path = "/handle/{handle}",
+    params(
+        ("handle" = String, Path, description = "Handle")
+    ),
+    tag = "Contract",
+    responses(
+        (status = OK, description = "Resolve a handle to its account", body = HandleResponse)
+    )
+)]
+pub async fn resolve_handle(
+    Path(handle): Path<String>,
+    State(state): State<ContractHandlerStore<IdentityContractState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let state = store.state.clone().ok_or(AppError(
+        StatusCode::NOT_FOUND,
+        anyhow!("Contract '{}' not found", store.contract_name),
+    ))?;
+
+    let account = state.resolve_handle(&handle).ok_or(AppError(
+        StatusCode::NOT_FOUND,
+        anyhow!("Handle '{handle}' not found"),
+    ))?;
+
+    Ok(Json(HandleResponse {
+        account: account.to_string(),
+        handle,
+    }))
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account}/handle",
+    params(
+        ("account" = String, Path, description = "Account")
+    ),
+    tag = "Contract",
+    responses(
+        (status = OK, description = "Get the handle of an account", body = HandleResponse)
+    )
+)]
+pub async fn get_handle(
+    Path(account): Path<Identity>,
+    State(state): State<ContractHandlerStore<IdentityContractState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let state = store.state.clone().ok_or(AppError(
+        StatusCode::NOT_FOUND,
+        anyhow!("Contract '{}' not found", store.contract_name),
+    ))?;
+
+    let handle = state.handle_of(&account.0).ok_or(AppError(
+        StatusCode::NOT_FOUND,
+        anyhow!("No handle for account '{}'", account.0),
+    ))?;
+
+    Ok(Json(HandleResponse {
+        handle: handle.to_string(),
+        account: account.0,
+    }))
+}
+
+#[derive(Serialize, ToSchema)]
+struct RecordsResponse {
+    account: String,
+    records: BTreeMap<String, String>,
+}
+
+#[utoipa::path(
+    get,
+    path = "/account/{account}/records",
+    params(
+        ("account" = String, Path, description = "Account")
+    ),
+    tag = "Contract",
+    responses(
+        (status = OK, description = "Get the text records of an account", body = RecordsResponse)
+    )
+)]
+pub async fn get_records(
+    Path(account): Path<Identity>,
+    State(state): State<ContractHandlerStore<IdentityContractState>>,
+) -> Result<impl IntoResponse, AppError> {
+    let store = state.read().await;
+    let state = store.state.clone().ok_or(AppError(
+        StatusCode::NOT_FOUND,
+        anyhow!("Contract '{}' not found", store.contract_name),
+    ))?;
+
+    let records = state.records_of(&account.0).cloned().unwrap_or_default();
+
+    Ok(Json(RecordsResponse {
+        account: account.0,
+        records,
     }))
 }
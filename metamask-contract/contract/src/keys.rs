@@ -0,0 +1,109 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use hex::encode;
+use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt;
+
+/// An identity key (the identity without its contract suffix), stored in binary when it has
+/// a canonical text form. Its text form round-trips exactly, so two distinct identities
+/// never share a key. JSON renders it as text.
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IdentityKey {
+    /// `0x` followed by the lowercase hex of an Ethereum address
+    Address([u8; 20]),
+    /// `multisig-` followed by the lowercase hex id of a multisig (see `MultisigInfo::id`)
+    Multisig([u8; 20]),
+    /// `0x` followed by the lowercase hex of a compressed public key, as used by passkeys
+    CompressedKey([u8; 33]),
+    /// Base58 32 bytes public key, as used by Ed25519 identities
+    Base58([u8; 32]),
+    /// Any other key, e.g. Bitcoin addresses
+    Other(String),
+}
+
+impl IdentityKey {
+    pub fn new(key: &str) -> Self {
+        if let Some(hex) = key.strip_prefix("0x").and_then(lowercase_hex) {
+            if let Ok(address) = hex.as_slice().try_into() {
+                return IdentityKey::Address(address);
+            }
+            if let Ok(public_key) = hex.as_slice().try_into() {
+                return IdentityKey::CompressedKey(public_key);
+            }
+        }
+        if let Some(hex) = key.strip_prefix("multisig-").and_then(lowercase_hex) {
+            if let Ok(id) = hex.as_slice().try_into() {
+                return IdentityKey::Multisig(id);
+            }
+        }
+        let mut bytes = [0u8; 32];
+        if bs58::decode(key).onto(&mut bytes).ok() == Some(bytes.len())
+            && bs58::encode(bytes).into_string() == key
+        {
+            return IdentityKey::Base58(bytes);
+        }
+        IdentityKey::Other(key.to_string())
+    }
+}
+
+/// Decodes `hex` if it is in its canonical lowercase form
+fn lowercase_hex(hex: &str) -> Option<Vec<u8>> {
+    let bytes = hex::decode(hex).ok()?;
+    (encode(&bytes) == hex).then_some(bytes)
+}
+
+impl From<&str> for IdentityKey {
+    fn from(key: &str) -> Self {
+        IdentityKey::new(key)
+    }
+}
+
+impl fmt::Display for IdentityKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityKey::Address(address) => write!(f, "0x{}", encode(address)),
+            IdentityKey::Multisig(id) => write!(f, "multisig-{}", encode(id)),
+            IdentityKey::CompressedKey(public_key) => write!(f, "0x{}", encode(public_key)),
+            IdentityKey::Base58(public_key) => {
+                write!(f, "{}", bs58::encode(public_key).into_string())
+            }
+            IdentityKey::Other(key) => write!(f, "{key}"),
+        }
+    }
+}
+
+impl Serialize for IdentityKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for IdentityKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <String as Deserialize>::deserialize(deserializer).map(|key| IdentityKey::new(&key))
+    }
+}
+
+/// Serializes an optional compressed secp256k1 public key as hex
+pub(crate) mod hex_public_key {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(
+        public_key: &Option<[u8; 33]>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        Serialize::serialize(&public_key.map(encode), serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<[u8; 33]>, D::Error> {
+        <Option<String> as Deserialize>::deserialize(deserializer)?
+            .map(|public_key| {
+                let bytes = hex::decode(public_key).map_err(D::Error::custom)?;
+                bytes
+                    .try_into()
+                    .map_err(|_| D::Error::custom("expected a 33 bytes public key"))
+            })
+            .transpose()
+    }
+}
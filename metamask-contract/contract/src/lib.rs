@@ -1,7 +1,11 @@
 use actions::IdentityAction;
+use admin::AdminCommand;
 use borsh::{BorshDeserialize, BorshSerialize};
-use hex::{decode, encode};
-use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use hex::encode;
+use keys::IdentityKey;
+use message::Deadline;
+use recovery::RecoveryConfig;
+use schemes::Scheme;
 use sdk::{utils::parse_raw_contract_input, HyleContract, RunResult};
 use serde::{Deserialize, Serialize};
 use sha2::Digest;
@@ -14,6 +18,24 @@ pub mod client;
 pub mod indexer;
 
 pub mod actions;
+pub mod admin;
+pub mod commitment;
+pub mod eip712;
+pub mod handles;
+pub mod keys;
+pub mod merkle;
+pub mod message;
+pub mod migration;
+pub mod records;
+pub mod recovery;
+pub mod schemes;
+pub mod siwe;
+
+pub use schemes::ethereum::{
+    k256_key_prehash_verifier, k256_key_verifier, k256_prehash_verifier, k256_recover_public_key,
+    k256_recover_public_key_prehash, k256_verifier,
+};
+pub use schemes::{SignatureError, SignatureScheme};
 
 extern crate alloc;
 
@@ -24,12 +46,22 @@ impl HyleContract for IdentityContractState {
         let (action, ctx) = parse_raw_contract_input::<IdentityAction>(input)?;
 
         let identity = input.identity.clone();
+        let tx_ctx = input.tx_ctx.as_ref();
         let contract_name = &input
             .blobs
             .get(input.index.0)
             .ok_or("No blob")?
             .contract_name;
 
+        // The node applies upgrades from the effects of the transaction
+        let upgrade = match &action {
+            IdentityAction::Admin {
+                command: AdminCommand::UpgradeProgram { program_id },
+                ..
+            } => Some(program_id.clone()),
+            _ => None,
+        };
+
         let program_output = if input.index.0 == 0 {
             // Identity blob should be at position 0
             let blobs = input
@@ -37,45 +69,281 @@ impl HyleContract for IdentityContractState {
                 .split_first()
                 .map(|(_, rest)| rest)
                 .ok_or("No blobs")?;
-            self.execute_action(action, contract_name, identity, blobs)?
+            self.execute_action(action, contract_name, identity, blobs, tx_ctx)?
         } else {
             // Otherwise, it's less efficient as need to clone blobs & the remove is O(n)
             let mut blobs = input.blobs.clone();
             blobs.remove(input.index.0);
-            self.execute_action(action, contract_name, identity, &blobs)?
+            self.execute_action(action, contract_name, identity, &blobs, tx_ctx)?
+        };
+
+        let effects = match upgrade {
+            Some(program_id) => {
+                let effect = admin::upgrade_effect(contract_name, &program_id, self.commit())?;
+                admin::keep_upgraded_state(contract_name, self);
+                vec![effect]
+            }
+            None => vec![],
         };
 
-        Ok((program_output, ctx, vec![]))
+        Ok((program_output, ctx, effects))
     }
 
     fn commit(&self) -> sdk::StateCommitment {
-        sdk::StateCommitment(self.as_bytes().expect("Failed to encode state"))
+        migration::VersionedState::V3(self.root()).commit()
     }
 }
 
+/// How the message authorizing a transaction was signed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SigningMode {
+    /// The identity's [`SignatureScheme`] over [`message::verify_message`]
+    /// (`personal_sign` for Ethereum identities)
+    PersonalSign,
+    /// `eth_signTypedData_v4` over a `HyleTx` struct (EIP-712), for Ethereum identities only
+    Eip712,
+}
+
 /// Struct to hold account's information
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
 pub struct AccountInfo {
-    pub pub_key_hash: String,
+    /// Compressed secp256k1 public key of Ethereum identities, recovered from the signature
+    /// registering their current key. Signatures are then checked directly against it,
+    /// instead of recovering their signer.
+    #[serde(default, with = "keys::hex_public_key")]
+    pub public_key: Option<[u8; 33]>,
+    /// Next nonce of the sequential lane 0
+    pub nonce: u128,
+    /// Next sequence of each other nonce lane that was used
+    #[serde(default)]
+    pub lanes: BTreeMap<u64, u128>,
+    #[serde(default)]
+    pub scheme: Scheme,
+    /// Key currently controlling the identity, when it was rotated away from the
+    /// identity key itself
+    #[serde(default)]
+    pub authority: Option<IdentityKey>,
+    /// Tombstone of a deleted identity. Its nonce is kept, and it can never be registered
+    /// again, so that old signatures cannot be replayed.
+    #[serde(default)]
+    pub deleted: bool,
+    /// Handle claimed by the identity, resolving to it in `IdentityContractState::handles`
+    #[serde(default)]
+    pub handle: Option<String>,
+}
+
+impl AccountInfo {
+    /// Key whose signatures are accepted for the identity `pub_key`
+    pub fn authority(&self, pub_key: &str) -> String {
+        match &self.authority {
+            Some(authority) => authority.to_string(),
+            None => pub_key.to_string(),
+        }
+    }
+
+    /// Checks that `nonce` was not used yet.
+    ///
+    /// The upper 64 bits of a nonce select a lane, and the lower 64 bits are a sequence in
+    /// that lane. Lanes are independent, so a wallet can sign transactions concurrently on
+    /// different lanes, while each lane only accepts increasing sequences. Lane 0 is the
+    /// sequential nonce stored in `nonce`.
+    pub fn check_nonce(&self, nonce: u128) -> Result<(), String> {
+        check_lane_nonce(self.nonce, &self.lanes, nonce)
+    }
+
+    /// Marks `nonce`, and all the previous sequences of its lane, as used
+    pub fn use_nonce(&mut self, nonce: u128) {
+        use_lane_nonce(&mut self.nonce, &mut self.lanes, nonce)
+    }
+}
+
+/// Builds the nonce of `sequence` in `lane` (see [`AccountInfo::check_nonce`])
+pub fn lane_nonce(lane: u64, sequence: u64) -> u128 {
+    (u128::from(lane) << 64) | u128::from(sequence)
+}
+
+fn split_nonce(nonce: u128) -> (u64, u64) {
+    ((nonce >> 64) as u64, nonce as u64)
+}
+
+/// Checks `nonce` against the next sequence of lane 0, `next`, and of the other `lanes`
+fn check_lane_nonce(next: u128, lanes: &BTreeMap<u64, u128>, nonce: u128) -> Result<(), String> {
+    let (lane, sequence) = split_nonce(nonce);
+    let next = match lane {
+        0 => next,
+        lane => lanes.get(&lane).copied().unwrap_or(0),
+    };
+    if u128::from(sequence) < next {
+        return Err("Invalid nonce".to_string());
+    }
+    Ok(())
+}
+
+/// Marks `nonce` as used in the next sequence of lane 0, `next`, or of the other `lanes`.
+/// Sequences are 64 bits, so the next one never overflows.
+fn use_lane_nonce(next: &mut u128, lanes: &mut BTreeMap<u64, u128>, nonce: u128) {
+    match split_nonce(nonce) {
+        (0, sequence) => *next = u128::from(sequence) + 1,
+        (lane, sequence) => {
+            lanes.insert(lane, u128::from(sequence) + 1);
+        }
+    }
+}
+
+/// A key delegated by an identity to sign transactions on its behalf, within a limited
+/// scope, without prompting the main wallet
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct SessionKey {
+    /// Block height from which the key is no longer accepted
+    pub expires_at: u64,
+    /// Contracts the key may send blobs to
+    pub allowed_contracts: Vec<sdk::ContractName>,
+    /// Remaining number of transactions the key may authorize
+    pub uses_left: u32,
+}
+
+/// An M-of-N identity controlled by registered Ethereum identities
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct MultisigInfo {
+    /// Registered Ethereum identities allowed to approve, sorted
+    pub signers: Vec<IdentityKey>,
+    /// Number of distinct signers required
+    pub threshold: u32,
+    /// Next nonce of the sequential lane 0
     pub nonce: u128,
+    /// Next sequence of each other nonce lane that was used
+    #[serde(default)]
+    pub lanes: BTreeMap<u64, u128>,
+}
+
+impl MultisigInfo {
+    /// Identity key of the multisig, derived from its distinct signers and threshold so that
+    /// a given configuration cannot be squatted, nor registered twice
+    pub fn id(signers: &[String], threshold: u32) -> String {
+        let mut signers = signers.to_vec();
+        signers.sort();
+        signers.dedup();
+        let encoded = borsh::to_vec(&(signers, threshold)).expect("Failed to encode multisig");
+        format!("multisig-{}", encode(&Keccak256::digest(encoded)[..20]))
+    }
+
+    /// Checks that `nonce` was not used yet (see [`AccountInfo::check_nonce`])
+    pub fn check_nonce(&self, nonce: u128) -> Result<(), String> {
+        check_lane_nonce(self.nonce, &self.lanes, nonce)
+    }
+
+    /// Marks `nonce`, and all the previous sequences of its lane, as used
+    pub fn use_nonce(&mut self, nonce: u128) {
+        use_lane_nonce(&mut self.nonce, &mut self.lanes, nonce)
+    }
+}
+
+/// Network id used when none is configured at contract registration
+pub const DEFAULT_NETWORK_ID: &str = "hyle-devnet";
+
+/// EIP-155 chain id SIWE messages are expected on when none is configured at contract
+/// registration
+pub const DEFAULT_SIWE_CHAIN_ID: u64 = 1;
+
+/// Number of blocks during which a migrated state keeps accepting the legacy messages
+pub const LEGACY_MESSAGES_WINDOW: u64 = 500_000;
+
+/// Until when the messages signed before they were bound to a contract and a network are
+/// accepted, along with the current ones
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, Eq, PartialEq,
+)]
+pub enum LegacyMessages {
+    Rejected,
+    /// Accepted in the blocks up to this height, included
+    Until(u64),
+    /// Accepted for [`LEGACY_MESSAGES_WINDOW`] blocks from the first transaction executed
+    /// after the migration of a state accepting them
+    AfterMigration,
 }
 
-/// The state of the contract, that is totally serialized on-chain
+/// The full state of the contract, kept off-chain. Only its Merkle root is committed
+/// on-chain (see [`commitment::IdentityContractRoot`]).
 #[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone)]
 pub struct IdentityContractState {
-    identities: BTreeMap<String, AccountInfo>,
+    identities: BTreeMap<IdentityKey, AccountInfo>,
+    multisigs: BTreeMap<IdentityKey, MultisigInfo>,
+    /// Session keys of each identity, by session key
+    session_keys: BTreeMap<IdentityKey, BTreeMap<IdentityKey, SessionKey>>,
+    /// Guardians of the identities that can be recovered
+    recoveries: BTreeMap<IdentityKey, RecoveryConfig>,
+    /// Identity key of each claimed handle
+    handles: BTreeMap<String, IdentityKey>,
+    /// Text records of each identity, by key
+    records: BTreeMap<IdentityKey, BTreeMap<String, String>>,
+    /// Network the contract is deployed on, bound into every signed message
+    network_id: String,
+    /// Until when the messages signed before they were bound to a contract and a network are
+    /// accepted
+    legacy_messages: LegacyMessages,
+    /// EIP-155 chain id of the SIWE registration messages
+    siwe_chain_id: u64,
+    /// Admin of the contract and its emergency controls
+    admin: Option<admin::Admin>,
 }
 
 /// Some helper methods for the state
 impl IdentityContractState {
-    pub fn new() -> Self {
+    pub fn new(
+        network_id: String,
+        legacy_messages: LegacyMessages,
+        siwe_chain_id: u64,
+        admin: Option<String>,
+    ) -> Self {
         IdentityContractState {
             identities: BTreeMap::new(),
+            multisigs: BTreeMap::new(),
+            session_keys: BTreeMap::new(),
+            recoveries: BTreeMap::new(),
+            handles: BTreeMap::new(),
+            records: BTreeMap::new(),
+            network_id,
+            legacy_messages,
+            siwe_chain_id,
+            admin: admin.as_deref().map(admin::Admin::new),
         }
     }
 
+    pub fn network_id(&self) -> &str {
+        &self.network_id
+    }
+
+    /// Whether the legacy messages are accepted in the block of `tx_ctx`
+    fn accepts_legacy_messages(&self, tx_ctx: Option<&sdk::TxContext>) -> bool {
+        match (self.legacy_messages, tx_ctx) {
+            (LegacyMessages::Until(height), Some(tx_ctx)) => tx_ctx.block_height.0 <= height,
+            _ => false,
+        }
+    }
+
+    /// Next nonce to use in `lane` (see [`AccountInfo::check_nonce`])
+    pub fn get_lane_nonce(&self, account: &str, lane: u64) -> Result<u128, &'static str> {
+        if lane == 0 {
+            return self.get_nonce(account);
+        }
+        let key = IdentityKey::new(account);
+        let lanes = match self.multisigs.get(&key) {
+            Some(multisig) => &multisig.lanes,
+            None => &self.identities.get(&key).ok_or("Identity not found")?.lanes,
+        };
+        let sequence = lanes.get(&lane).copied().unwrap_or(0);
+        let sequence = u64::try_from(sequence).map_err(|_| "Nonce lane exhausted")?;
+        Ok(lane_nonce(lane, sequence))
+    }
+
     pub fn get_nonce(&self, account: &str) -> Result<u128, &'static str> {
-        let info = self.identities.get(account).ok_or("Identity not found")?;
+        if let Some(multisig) = self.multisigs.get(&IdentityKey::new(account)) {
+            return Ok(multisig.nonce);
+        }
+        let info = self
+            .identities
+            .get(&IdentityKey::new(account))
+            .ok_or("Identity not found")?;
         Ok(info.nonce)
     }
 }
@@ -87,109 +355,714 @@ impl IdentityContractState {
         contract_name: &sdk::ContractName,
         account: sdk::Identity,
         blobs: &[sdk::Blob],
+        tx_ctx: Option<&sdk::TxContext>,
     ) -> Result<String, String> {
-        if !account.0.ends_with(&contract_name.0) {
-            return Err(format!(
-                "Invalid account extension. '.{contract_name}' expected."
-            ));
+        let pub_key = account_key(&account, contract_name)?;
+        self.check_controls(pub_key, &action)?;
+
+        // The legacy window of a migrated state starts with its first transaction
+        if let (LegacyMessages::AfterMigration, Some(tx_ctx)) = (self.legacy_messages, tx_ctx) {
+            self.legacy_messages =
+                LegacyMessages::Until(tx_ctx.block_height.0 + LEGACY_MESSAGES_WINDOW);
         }
-        let pub_key = account
-            .0
-            .trim_end_matches(&contract_name.0)
-            .trim_end_matches(".");
 
-        match action {
+        let (nonce, signature, mode, valid_until) = match action {
             IdentityAction::RegisterIdentity { signature } => {
-                self.register_identity(pub_key, &signature)
+                return self.register_identity(
+                    pub_key,
+                    Scheme::Ethereum,
+                    contract_name,
+                    &signature,
+                    tx_ctx,
+                );
             }
-            IdentityAction::VerifyIdentity { nonce, signature } => {
-                match self.verify_identity(pub_key, nonce, blobs, &signature) {
-                    Ok(true) => Ok(format!("Identity verified for account: {}", account)),
-                    Ok(false) => Err(format!(
-                        "Identity verification failed for account: {}",
-                        account
-                    )),
-                    Err(err) => Err(format!("Error verifying identity: {}", err)),
-                }
+            IdentityAction::RegisterIdentityWithScheme { scheme, signature } => {
+                return self.register_identity(pub_key, scheme, contract_name, &signature, tx_ctx);
+            }
+            IdentityAction::RegisterIdentityWithSiwe { message, signature } => {
+                return self.register_identity_with_siwe(
+                    pub_key,
+                    contract_name,
+                    &message,
+                    &signature,
+                    tx_ctx,
+                );
+            }
+            IdentityAction::RegisterMultisig { signers, threshold } => {
+                return self.register_multisig(pub_key, signers, threshold);
+            }
+            IdentityAction::VerifyMultisig {
+                nonce,
+                signatures,
+                valid_until,
+            } => {
+                check_deadline(valid_until, tx_ctx)?;
+                return self
+                    .verify_multisig(
+                        pub_key,
+                        nonce,
+                        valid_until,
+                        contract_name,
+                        blobs,
+                        &signatures,
+                    )
+                    .map(|_| format!("Identity verified for account: {}", account))
+                    .map_err(|err| format!("Error verifying identity: {}", err));
+            }
+            IdentityAction::RotateKey {
+                nonce,
+                new_key,
+                signature,
+                new_key_signature,
+            } => {
+                return self.rotate_key(
+                    pub_key,
+                    nonce,
+                    new_key,
+                    contract_name,
+                    &signature,
+                    &new_key_signature,
+                );
+            }
+            IdentityAction::RegisterSessionKey {
+                nonce,
+                key,
+                expires_at,
+                allowed_contracts,
+                max_uses,
+                signature,
+            } => {
+                let session_key = SessionKey {
+                    expires_at,
+                    allowed_contracts,
+                    uses_left: max_uses,
+                };
+                return self.register_session_key(
+                    pub_key,
+                    nonce,
+                    key,
+                    session_key,
+                    contract_name,
+                    &signature,
+                );
+            }
+            IdentityAction::VerifyIdentityWithSessionKey {
+                nonce,
+                key,
+                signature,
+                valid_until,
+            } => {
+                check_deadline(valid_until, tx_ctx)?;
+                return self
+                    .verify_with_session_key(
+                        pub_key,
+                        nonce,
+                        valid_until,
+                        &key,
+                        contract_name,
+                        blobs,
+                        tx_ctx,
+                        &signature,
+                    )
+                    .map(|_| format!("Identity verified for account: {}", account))
+                    .map_err(|err| format!("Error verifying identity: {}", err));
+            }
+            IdentityAction::ClaimHandle {
+                nonce,
+                handle,
+                signature,
+            } => {
+                return self.claim_handle(pub_key, nonce, handle, contract_name, &signature);
+            }
+            IdentityAction::TransferHandle {
+                nonce,
+                to,
+                signature,
+            } => {
+                return self.transfer_handle(pub_key, nonce, &to, contract_name, &signature);
+            }
+            IdentityAction::ReleaseHandle { nonce, signature } => {
+                return self.release_handle(pub_key, nonce, contract_name, &signature);
+            }
+            IdentityAction::SetRecord {
+                nonce,
+                key,
+                value,
+                signature,
+            } => {
+                return self.set_record(pub_key, nonce, key, value, contract_name, &signature);
+            }
+            IdentityAction::DeleteIdentity { nonce, signature } => {
+                return self.delete_identity(pub_key, nonce, contract_name, &signature);
+            }
+            IdentityAction::Admin {
+                nonce,
+                command,
+                signature,
+            } => {
+                return self.admin_command(pub_key, nonce, command, contract_name, &signature);
+            }
+            IdentityAction::SetGuardians {
+                nonce,
+                guardians,
+                threshold,
+                delay,
+                signature,
+            } => {
+                return self.set_guardians(
+                    pub_key,
+                    nonce,
+                    guardians,
+                    threshold,
+                    delay,
+                    contract_name,
+                    &signature,
+                );
+            }
+            IdentityAction::InitiateRecovery {
+                nonce,
+                new_key,
+                signatures,
+            } => {
+                return self.initiate_recovery(
+                    pub_key,
+                    nonce,
+                    new_key,
+                    contract_name,
+                    &signatures,
+                    tx_ctx,
+                );
             }
+            IdentityAction::CancelRecovery { nonce, signature } => {
+                return self.cancel_recovery(pub_key, nonce, contract_name, &signature);
+            }
+            IdentityAction::CompleteRecovery => {
+                return self.complete_recovery(pub_key, tx_ctx);
+            }
+            IdentityAction::VerifyIdentity {
+                nonce,
+                signature,
+                valid_until,
+            } => (nonce, signature, SigningMode::PersonalSign, valid_until),
+            IdentityAction::VerifyIdentityTyped {
+                nonce,
+                signature,
+                valid_until,
+            } => (nonce, signature, SigningMode::Eip712, valid_until),
+        };
+
+        check_deadline(valid_until, tx_ctx)?;
+        match self.verify_identity(
+            pub_key,
+            nonce,
+            valid_until,
+            contract_name,
+            blobs,
+            &signature,
+            mode,
+            tx_ctx,
+        ) {
+            Ok(()) => Ok(format!("Identity verified for account: {}", account)),
+            Err(err) => Err(format!("Error verifying identity: {}", err)),
         }
     }
 
-    fn register_identity(&mut self, pub_key: &str, signature: &str) -> Result<String, String> {
-        // Parse the signature
-        let valid = k256_verifier(pub_key, signature, "hyle registration");
+    fn register_identity(
+        &mut self,
+        pub_key: &str,
+        scheme: Scheme,
+        contract_name: &sdk::ContractName,
+        signature: &str,
+        tx_ctx: Option<&sdk::TxContext>,
+    ) -> Result<String, String> {
+        let verifier = scheme.verifier();
+        verifier
+            .validate_key(pub_key)
+            .map_err(|err| format!("Invalid identity {pub_key}: {err}"))?;
 
-        if !valid {
-            return Err(format!(
-                "Invalid register signature for {pub_key}, {signature}"
-            ));
+        // Parse the signature
+        let message = message::registration_message(contract_name, &self.network_id);
+        let public_key = if scheme == Scheme::Ethereum {
+            let mut result = k256_recover_public_key(pub_key, signature, &message);
+            // Legacy messages were only ever signed by Ethereum accounts
+            if result.is_err() && self.accepts_legacy_messages(tx_ctx) {
+                result = k256_recover_public_key(
+                    pub_key,
+                    signature,
+                    message::LEGACY_REGISTRATION_MESSAGE,
+                );
+            }
+            result.map(Some)
+        } else {
+            verifier.verify(pub_key, signature, &message).map(|_| None)
         }
+        .map_err(|err| format!("Invalid register signature for {pub_key}: {err}"))?;
+
+        self.insert_identity(pub_key, scheme, public_key)
+    }
+
+    fn register_identity_with_siwe(
+        &mut self,
+        pub_key: &str,
+        contract_name: &sdk::ContractName,
+        siwe_message: &str,
+        signature: &str,
+        tx_ctx: Option<&sdk::TxContext>,
+    ) -> Result<String, String> {
+        Scheme::Ethereum
+            .verifier()
+            .validate_key(pub_key)
+            .map_err(|err| format!("Invalid identity {pub_key}: {err}"))?;
+
+        // The transaction timestamp is in milliseconds
+        let now = tx_ctx.map(|tx_ctx| tx_ctx.timestamp);
+        siwe::SiweMessage::parse(siwe_message)?.validate_registration(
+            pub_key,
+            contract_name,
+            &self.network_id,
+            self.siwe_chain_id,
+            now,
+        )?;
+        let public_key = k256_recover_public_key(pub_key, signature, siwe_message)
+            .map_err(|err| format!("Invalid register signature for {pub_key}: {err}"))?;
 
-        let pub_key_hash = Keccak256::digest(pub_key.as_bytes());
-        let pub_key_hash_hex = encode(pub_key_hash);
+        self.insert_identity(pub_key, Scheme::Ethereum, Some(public_key))
+    }
 
+    fn insert_identity(
+        &mut self,
+        pub_key: &str,
+        scheme: Scheme,
+        public_key: Option<[u8; 33]>,
+    ) -> Result<String, String> {
         let account_info = AccountInfo {
-            pub_key_hash: pub_key_hash_hex,
+            public_key,
             nonce: 0,
+            lanes: BTreeMap::new(),
+            scheme,
+            authority: None,
+            deleted: false,
+            handle: None,
         };
 
-        if self
-            .identities
-            .insert(pub_key.to_string(), account_info)
-            .is_some()
-        {
-            return Err("Identity already exists".to_string());
+        let key = IdentityKey::new(pub_key);
+        match self.identities.get(&key) {
+            Some(info) if info.deleted => return Err("Identity was deleted".to_string()),
+            Some(_) => return Err("Identity already exists".to_string()),
+            None => {}
         }
+        self.identities.insert(key, account_info);
 
         Ok("Identity registered".to_string())
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn verify_identity(
         &mut self,
         pub_key: &str,
         nonce: u128,
+        valid_until: Option<Deadline>,
+        contract_name: &sdk::ContractName,
         blobs: &[sdk::Blob],
         signature: &str,
-    ) -> Result<bool, String> {
-        match self.identities.get_mut(pub_key) {
+        mode: SigningMode,
+        tx_ctx: Option<&sdk::TxContext>,
+    ) -> Result<(), String> {
+        let accepts_legacy_messages = self.accepts_legacy_messages(tx_ctx);
+        match self.identities.get_mut(&IdentityKey::new(pub_key)) {
+            Some(stored_info) if stored_info.deleted => Err("Identity was deleted".to_string()),
             Some(stored_info) => {
-                if nonce < stored_info.nonce {
-                    return Err("Invalid nonce".to_string());
-                }
-                // Compute Keccak256 hash of the account (to match register_identity)
-                let pub_key_hash = Keccak256::digest(pub_key.as_bytes());
-                let computed_hash = encode(pub_key_hash);
+                stored_info.check_nonce(nonce)?;
 
-                if *stored_info.pub_key_hash != computed_hash {
-                    return Ok(false);
-                }
+                match mode {
+                    SigningMode::PersonalSign => {
+                        let message = message::verify_message(
+                            contract_name,
+                            &self.network_id,
+                            pub_key,
+                            nonce,
+                            valid_until,
+                            blobs,
+                        );
 
-                // const message = `verify ${nonce} ${blobs.map((blob) => blob.contract_name + " " + blob.data).join(" ")}`;
+                        let scheme = stored_info.scheme;
+                        let authority = stored_info.authority(pub_key);
+                        // Ethereum identities are checked against their public key, recovered
+                        // once if it is not known yet
+                        let verify = |message: &str| match (scheme, &stored_info.public_key) {
+                            (Scheme::Ethereum, Some(public_key)) => {
+                                k256_key_verifier(public_key, signature, message).map(|_| None)
+                            }
+                            (Scheme::Ethereum, None) => {
+                                k256_recover_public_key(&authority, signature, message).map(Some)
+                            }
+                            _ => scheme
+                                .verifier()
+                                .verify(&authority, signature, message)
+                                .map(|_| None),
+                        };
+                        let mut result = verify(&message);
+                        // Legacy messages have no deadline
+                        if result.is_err()
+                            && valid_until.is_none()
+                            && accepts_legacy_messages
+                            && scheme == Scheme::Ethereum
+                        {
+                            result = verify(&message::legacy_verify_message(nonce, blobs));
+                        }
 
-                let message = blobs
-                    .iter()
-                    .map(|blob| format!("{} {:?}", blob.contract_name, blob.data.0))
-                    .collect::<Vec<String>>()
-                    .join(" ");
-                let message = format!("verify {} {}", nonce, message);
+                        let recovered_key = result.map_err(|err| {
+                            format!("Invalid signature for message {message}: {err}")
+                        })?;
+                        if recovered_key.is_some() {
+                            stored_info.public_key = recovered_key;
+                        }
+                    }
+                    SigningMode::Eip712 => {
+                        if stored_info.scheme != Scheme::Ethereum {
+                            return Err(
+                                "EIP-712 signatures are only supported by Ethereum identities"
+                                    .to_string(),
+                            );
+                        }
 
-                let valid = k256_verifier(pub_key, signature, &message);
+                        let digest = eip712::hyle_tx_digest(
+                            contract_name,
+                            &self.network_id,
+                            pub_key,
+                            nonce,
+                            valid_until,
+                            blobs,
+                        );
 
-                if !valid {
-                    return Err(format!("Invalid signature for message {message}"));
+                        let authority = stored_info.authority(pub_key);
+                        let recovered_key = match &stored_info.public_key {
+                            Some(public_key) => {
+                                k256_key_prehash_verifier(public_key, signature, &digest)
+                                    .map(|_| None)
+                            }
+                            None => k256_recover_public_key_prehash(&authority, signature, &digest)
+                                .map(Some),
+                        }
+                        .map_err(|err| {
+                            format!(
+                                "Invalid typed data signature for digest {}: {err}",
+                                encode(digest)
+                            )
+                        })?;
+                        if recovered_key.is_some() {
+                            stored_info.public_key = recovered_key;
+                        }
+                    }
                 }
 
-                stored_info.nonce = nonce + 1;
-                Ok(true)
+                stored_info.use_nonce(nonce);
+                Ok(())
             }
             None => Err("Identity not found".to_string()),
         }
     }
 
+    fn rotate_key(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        new_key: String,
+        contract_name: &sdk::ContractName,
+        signature: &str,
+        new_key_signature: &str,
+    ) -> Result<String, String> {
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+
+        let verifier = stored_info.scheme.verifier();
+        verifier
+            .validate_key(&new_key)
+            .map_err(|err| format!("Invalid new key {new_key}: {err}"))?;
+        let authority = stored_info.authority(pub_key);
+        if new_key == authority {
+            return Err(format!("{new_key} already controls {pub_key}"));
+        }
+
+        // Both keys sign the same message: the current one hands over the identity, the
+        // new one proves it is controlled by the caller
+        let message =
+            message::rotation_message(contract_name, &self.network_id, pub_key, &new_key, nonce);
+        verifier
+            .verify(&authority, signature, &message)
+            .map_err(|err| format!("Invalid signature of the current key: {err}"))?;
+        let public_key = if stored_info.scheme == Scheme::Ethereum {
+            k256_recover_public_key(&new_key, new_key_signature, &message).map(Some)
+        } else {
+            verifier
+                .verify(&new_key, new_key_signature, &message)
+                .map(|_| None)
+        }
+        .map_err(|err| format!("Invalid signature of the new key: {err}"))?;
+
+        stored_info.authority = (new_key != pub_key).then(|| IdentityKey::new(&new_key));
+        stored_info.public_key = public_key;
+        stored_info.use_nonce(nonce);
+        // Session keys may have been delegated by a compromised key
+        self.session_keys.remove(&IdentityKey::new(pub_key));
+        // The owner is in control, a pending recovery is moot
+        if let Some(config) = self.recoveries.get_mut(&IdentityKey::new(pub_key)) {
+            config.pending = None;
+        }
+
+        Ok(format!("Key rotated for identity {pub_key}"))
+    }
+
+    fn delete_identity(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        contract_name: &sdk::ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+
+        let message = message::deletion_message(contract_name, &self.network_id, pub_key, nonce);
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+
+        stored_info.deleted = true;
+        stored_info.authority = None;
+        stored_info.public_key = None;
+        stored_info.use_nonce(nonce);
+        if let Some(handle) = stored_info.handle.take() {
+            self.handles.remove(&handle);
+        }
+        let key = IdentityKey::new(pub_key);
+        self.session_keys.remove(&key);
+        self.recoveries.remove(&key);
+        self.records.remove(&key);
+
+        Ok(format!("Identity {pub_key} deleted"))
+    }
+
+    fn register_session_key(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        key: String,
+        session_key: SessionKey,
+        contract_name: &sdk::ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+
+        // Session keys are generated by the wallet, whatever the scheme of the identity
+        Scheme::Ethereum
+            .verifier()
+            .validate_key(&key)
+            .map_err(|err| format!("Invalid session key {key}: {err}"))?;
+        if session_key.uses_left == 0 {
+            return Err("A session key must allow at least one use".to_string());
+        }
+
+        let message = message::session_key_message(
+            contract_name,
+            &self.network_id,
+            pub_key,
+            &key,
+            &session_key,
+            nonce,
+        );
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+
+        stored_info.use_nonce(nonce);
+        self.session_keys
+            .entry(IdentityKey::new(pub_key))
+            .or_default()
+            .insert(IdentityKey::new(&key), session_key);
+
+        Ok(format!("Session key {key} registered for {pub_key}"))
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn verify_with_session_key(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        valid_until: Option<Deadline>,
+        key: &str,
+        contract_name: &sdk::ContractName,
+        blobs: &[sdk::Blob],
+        tx_ctx: Option<&sdk::TxContext>,
+        signature: &str,
+    ) -> Result<(), String> {
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+
+        let session_keys = self
+            .session_keys
+            .get_mut(&IdentityKey::new(pub_key))
+            .ok_or(format!("No session key for {pub_key}"))?;
+        let session_key = session_keys
+            .get_mut(&IdentityKey::new(key))
+            .ok_or(format!("Unknown session key {key}"))?;
+
+        // Expiry can only be checked against the block the transaction lands in
+        let tx_ctx = tx_ctx.ok_or("Session keys require the transaction context")?;
+        if tx_ctx.block_height.0 >= session_key.expires_at {
+            return Err(format!(
+                "Session key {key} expired at block {}",
+                session_key.expires_at
+            ));
+        }
+        if let Some(blob) = blobs
+            .iter()
+            .find(|blob| !session_key.allowed_contracts.contains(&blob.contract_name))
+        {
+            return Err(format!(
+                "Session key {key} is not allowed to call {}",
+                blob.contract_name
+            ));
+        }
+
+        let message = message::session_verify_message(
+            contract_name,
+            &self.network_id,
+            pub_key,
+            nonce,
+            valid_until,
+            blobs,
+        );
+        k256_verifier(key, signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+
+        session_key.uses_left -= 1;
+        if session_key.uses_left == 0 {
+            session_keys.remove(&IdentityKey::new(key));
+        }
+        stored_info.use_nonce(nonce);
+        Ok(())
+    }
+
+    fn register_multisig(
+        &mut self,
+        pub_key: &str,
+        signers: Vec<String>,
+        threshold: u32,
+    ) -> Result<String, String> {
+        let expected_id = MultisigInfo::id(&signers, threshold);
+        if pub_key != expected_id {
+            return Err(format!(
+                "Invalid multisig identity {pub_key}, expected {expected_id}"
+            ));
+        }
+
+        let mut signers: Vec<IdentityKey> = signers
+            .iter()
+            .map(|signer| IdentityKey::new(signer))
+            .collect();
+        signers.sort();
+        signers.dedup();
+        if threshold == 0 || threshold as usize > signers.len() {
+            return Err(format!(
+                "Invalid threshold {threshold} for {} distinct signers",
+                signers.len()
+            ));
+        }
+        for signer in &signers {
+            match self.identities.get(signer) {
+                Some(info) if info.deleted => return Err(format!("Signer {signer} was deleted")),
+                Some(info) if info.scheme == Scheme::Ethereum => {}
+                Some(_) => return Err(format!("Signer {signer} is not an Ethereum identity")),
+                None => return Err(format!("Signer {signer} is not registered")),
+            }
+        }
+
+        if self.multisigs.contains_key(&IdentityKey::new(pub_key)) {
+            return Err("Identity already exists".to_string());
+        }
+        self.multisigs.insert(
+            IdentityKey::new(pub_key),
+            MultisigInfo {
+                signers,
+                threshold,
+                nonce: 0,
+                lanes: BTreeMap::new(),
+            },
+        );
+
+        Ok("Multisig registered".to_string())
+    }
+
+    fn verify_multisig(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        valid_until: Option<Deadline>,
+        contract_name: &sdk::ContractName,
+        blobs: &[sdk::Blob],
+        signatures: &BTreeMap<String, String>,
+    ) -> Result<(), String> {
+        let multisig = self
+            .multisigs
+            .get_mut(&IdentityKey::new(pub_key))
+            .ok_or("Identity not found")?;
+        multisig.check_nonce(nonce)?;
+
+        let message = message::multisig_verify_message(
+            contract_name,
+            &self.network_id,
+            pub_key,
+            nonce,
+            valid_until,
+            blobs,
+        );
+
+        // Signatures are keyed by signer, so each signer approves at most once
+        let mut approvals = 0;
+        for (signer, signature) in signatures {
+            if multisig
+                .signers
+                .binary_search(&IdentityKey::new(signer))
+                .is_err()
+            {
+                return Err(format!("{signer} is not a signer of {pub_key}"));
+            }
+            // The approvals of frozen signers are not counted
+            if self
+                .admin
+                .as_ref()
+                .is_some_and(|admin| admin.is_frozen(signer))
+            {
+                continue;
+            }
+            let signer_info = self
+                .identities
+                .get(&IdentityKey::new(signer))
+                .filter(|info| !info.deleted)
+                .ok_or(format!("Signer {signer} is no longer registered"))?;
+            k256_verifier(&signer_info.authority(signer), signature, &message).map_err(|err| {
+                format!("Invalid signature of {signer} for message {message}: {err}")
+            })?;
+            approvals += 1;
+        }
+
+        if approvals < multisig.threshold {
+            return Err(format!(
+                "{approvals} approvals out of the {} required",
+                multisig.threshold
+            ));
+        }
+
+        multisig.use_nonce(nonce);
+        Ok(())
+    }
+
     #[allow(dead_code)]
     pub fn get_identity_info(&self, account: &str) -> Result<AccountInfo, &'static str> {
         self.identities
-            .get(account)
+            .get(&IdentityKey::new(account))
             .cloned()
             .ok_or("Identity not found")
     }
@@ -199,67 +1072,511 @@ impl IdentityContractState {
     }
 }
 
+/// Identity key of `account`, without its contract suffix
+fn account_key<'a>(
+    account: &'a sdk::Identity,
+    contract_name: &sdk::ContractName,
+) -> Result<&'a str, String> {
+    if !account.0.ends_with(&contract_name.0) {
+        return Err(format!(
+            "Invalid account extension. '.{contract_name}' expected."
+        ));
+    }
+    Ok(account
+        .0
+        .trim_end_matches(&contract_name.0)
+        .trim_end_matches("."))
+}
+
+/// Checks that a signed authorization is used before its deadline, if it has one
+fn check_deadline(
+    valid_until: Option<Deadline>,
+    tx_ctx: Option<&sdk::TxContext>,
+) -> Result<(), String> {
+    let Some(deadline) = valid_until else {
+        return Ok(());
+    };
+    let tx_ctx = tx_ctx.ok_or("Signature deadlines require the transaction context")?;
+    let expired = match deadline {
+        Deadline::BlockHeight(height) => tx_ctx.block_height.0 > height,
+        Deadline::Timestamp(timestamp) => tx_ctx.timestamp > timestamp,
+    };
+    if expired {
+        return Err(format!(
+            "Signature expired: it was only valid until {deadline}"
+        ));
+    }
+    Ok(())
+}
+
+/// Looks up an identity that was not deleted, and checks that `nonce` was not used yet
+fn active_identity<'a>(
+    identities: &'a mut BTreeMap<IdentityKey, AccountInfo>,
+    pub_key: &str,
+    nonce: u128,
+) -> Result<&'a mut AccountInfo, String> {
+    let stored_info = identities
+        .get_mut(&IdentityKey::new(pub_key))
+        .ok_or("Identity not found")?;
+    if stored_info.deleted {
+        return Err("Identity was deleted".to_string());
+    }
+    stored_info.check_nonce(nonce)?;
+    Ok(stored_info)
+}
+
 impl Default for IdentityContractState {
     fn default() -> Self {
-        Self::new()
+        Self::new(
+            DEFAULT_NETWORK_ID.to_string(),
+            LegacyMessages::Rejected,
+            DEFAULT_SIWE_CHAIN_ID,
+            None,
+        )
     }
 }
 
-/// Helpers to transform the contrat's state commitment in its full-state.
-/// In an optimal version, you would here only returns a hash of the state,
-/// while storing the full-state off-chain
+/// Rebuilds the full state from its commitment. Versioned commitments only hold the Merkle
+/// root, so this is only possible for the unversioned state of the first deployments, and
+/// for the empty state the contract is registered with: the full state is then kept
+/// off-chain by replaying the transactions. A contract re-registered by an upgrade gets
+/// back the full state this process held after executing it.
 impl TryFrom<sdk::StateCommitment> for IdentityContractState {
     type Error = anyhow::Error;
 
-    fn try_from(state: sdk::StateCommitment) -> Result<Self, Self::Error> {
-        borsh::from_slice(&state.0)
-            .map_err(|_| anyhow::anyhow!("Could not decode identity state".to_string()))
+    fn try_from(commitment: sdk::StateCommitment) -> Result<Self, Self::Error> {
+        let state: migration::VersionedState = borsh::from_slice(&commitment.0)
+            .map_err(|_| anyhow::anyhow!("Could not decode identity state".to_string()))?;
+        let mut state = match state {
+            migration::VersionedState::V0(state) => return Ok(state.into()),
+            state => state,
+        };
+        state.migrate();
+        match state {
+            migration::VersionedState::V3(root) if root.root == merkle::EMPTY => {
+                let mut state = Self::new(
+                    root.network_id,
+                    root.legacy_messages,
+                    root.siwe_chain_id,
+                    None,
+                );
+                state.admin = root.admin;
+                Ok(state)
+            }
+            _ => admin::upgraded_state(&commitment).ok_or_else(|| {
+                anyhow::anyhow!("The full identity state cannot be rebuilt from its Merkle root")
+            }),
+        }
     }
 }
 
-pub fn k256_verifier(mut pub_key: &str, mut signature_hex: &str, message: &str) -> bool {
-    pub_key = sanitize_hex(pub_key);
-    signature_hex = sanitize_hex(signature_hex);
-
-    let msg = message.as_bytes();
-
-    // Apply Ethereum Signed Message Prefix (EIP-191)
-    let eth_message = format!(
-        "\x19Ethereum Signed Message:\n{}{}",
-        msg.len(),
-        String::from_utf8_lossy(msg)
-    );
-
-    let signature_bytes = decode(signature_hex).expect("Invalid hex string");
-    let recovery_id_byte = signature_bytes.last().copied().expect("Signature is empty");
-    // Normalize Ethereum's recovery ID
-    let recovery_id_byte = if recovery_id_byte >= 27 {
-        recovery_id_byte - 27
-    } else {
-        recovery_id_byte
-    };
-    let recovery_id = RecoveryId::try_from(recovery_id_byte).expect("Wrong recover id byte");
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use k256::ecdsa::SigningKey;
+    use sdk::{Blob, ContractName};
 
-    let signature = Signature::from_slice(&signature_bytes[..64]).expect("Wrong signature");
+    pub(crate) fn contract_name() -> ContractName {
+        "mmid".into()
+    }
 
-    let recovered_key = VerifyingKey::recover_from_digest(
-        Keccak256::new_with_prefix(eth_message),
-        &signature,
-        recovery_id,
-    )
-    .expect("Error when recovering public key");
+    pub(crate) fn signing_key(seed: u8) -> SigningKey {
+        SigningKey::from_slice(&[seed; 32]).unwrap()
+    }
 
-    let encoded_point = recovered_key.to_encoded_point(false);
-    let pub_key_bytes = encoded_point.as_bytes();
+    /// Lowercase Ethereum address of `key`
+    pub(crate) fn address(key: &SigningKey) -> String {
+        let point = key.verifying_key().to_encoded_point(false);
+        format!(
+            "0x{}",
+            encode(&Keccak256::digest(&point.as_bytes()[1..])[12..])
+        )
+    }
 
-    // Hash the public key (skip the first byte which is always 0x04)
-    let hashed_key = Keccak256::digest(&pub_key_bytes[1..]);
+    pub(crate) fn sign_prehash(key: &SigningKey, prehash: &[u8]) -> String {
+        let (signature, recovery_id) = key.sign_prehash_recoverable(prehash).unwrap();
+        let mut bytes = signature.to_bytes().to_vec();
+        bytes.push(27 + recovery_id.to_byte());
+        format!("0x{}", encode(bytes))
+    }
 
-    // Extract the last 20 bytes (Ethereum address format)
-    let recovered_address = &hashed_key[12..]; // Last 20 bytes
-    hex::encode(recovered_address) == pub_key
-}
+    pub(crate) fn personal_sign(key: &SigningKey, message: &str) -> String {
+        let prefixed = format!("\x19Ethereum Signed Message:\n{}{message}", message.len());
+        sign_prehash(key, &Keccak256::digest(prefixed))
+    }
+
+    /// Executes `action` sent by the identity key `pub_key`, along with `blobs`
+    pub(crate) fn run(
+        state: &mut IdentityContractState,
+        pub_key: &str,
+        action: IdentityAction,
+        blobs: &[Blob],
+        tx_ctx: Option<sdk::TxContext>,
+    ) -> Result<String, String> {
+        let mut all_blobs = vec![action.as_blob(contract_name())];
+        all_blobs.extend_from_slice(blobs);
+        let input = sdk::ContractInput {
+            identity: format!("{pub_key}.{}", contract_name()).into(),
+            blobs: all_blobs,
+            tx_ctx,
+            ..Default::default()
+        };
+        state.execute(&input).map(|(output, ..)| output)
+    }
+
+    /// Registers the Ethereum identity of `key`, returning its identity key
+    pub(crate) fn register(state: &mut IdentityContractState, key: &SigningKey) -> String {
+        let message = message::registration_message(&contract_name(), state.network_id());
+        let action = IdentityAction::RegisterIdentity {
+            signature: personal_sign(key, &message),
+        };
+        run(state, &address(key), action, &[], None).unwrap();
+        address(key)
+    }
+
+    pub(crate) fn verify(
+        state: &mut IdentityContractState,
+        pub_key: &str,
+        key: &SigningKey,
+        nonce: u128,
+    ) -> Result<String, String> {
+        let message = message::verify_message(
+            &contract_name(),
+            state.network_id(),
+            pub_key,
+            nonce,
+            None,
+            &[],
+        );
+        let action = IdentityAction::VerifyIdentity {
+            nonce,
+            signature: personal_sign(key, &message),
+            valid_until: None,
+        };
+        run(state, pub_key, action, &[], None)
+    }
+
+    /// Rotates the key of `pub_key`, controlled by `key`, to the identity key of `new_key`
+    fn rotate(
+        state: &mut IdentityContractState,
+        pub_key: &str,
+        key: &SigningKey,
+        new_key: &SigningKey,
+        nonce: u128,
+    ) {
+        let message = message::rotation_message(
+            &contract_name(),
+            state.network_id(),
+            pub_key,
+            &address(new_key),
+            nonce,
+        );
+        let action = IdentityAction::RotateKey {
+            nonce,
+            new_key: address(new_key),
+            signature: personal_sign(key, &message),
+            new_key_signature: personal_sign(new_key, &message),
+        };
+        run(state, pub_key, action, &[], None).unwrap();
+    }
+
+    #[test]
+    fn signatures_cannot_be_replayed_across_identities_sharing_a_key() {
+        let mut state = IdentityContractState::default();
+        let (alice_key, other_key) = (signing_key(1), signing_key(2));
+        let alice = register(&mut state, &alice_key);
+        let other = register(&mut state, &other_key);
+        // Both identities are now controlled by the key of alice
+        rotate(&mut state, &other, &other_key, &alice_key, 0);
+
+        let blobs = [Blob {
+            contract_name: "hyllar".into(),
+            data: sdk::BlobData(vec![1, 2, 3]),
+        }];
+        let message = message::verify_message(
+            &contract_name(),
+            DEFAULT_NETWORK_ID,
+            &alice,
+            1,
+            None,
+            &blobs,
+        );
+        let action = IdentityAction::VerifyIdentity {
+            nonce: 1,
+            signature: personal_sign(&alice_key, &message),
+            valid_until: None,
+        };
+        assert!(run(&mut state, &other, action.clone(), &blobs, None).is_err());
+        run(&mut state, &alice, action, &blobs, None).unwrap();
+
+        let digest = eip712::hyle_tx_digest(
+            &contract_name(),
+            DEFAULT_NETWORK_ID,
+            &alice,
+            2,
+            None,
+            &blobs,
+        );
+        let action = IdentityAction::VerifyIdentityTyped {
+            nonce: 2,
+            signature: sign_prehash(&alice_key, &digest),
+            valid_until: None,
+        };
+        assert!(run(&mut state, &other, action.clone(), &blobs, None).is_err());
+        run(&mut state, &alice, action, &blobs, None).unwrap();
+
+        // The key still authorizes the other identity with its own signatures
+        verify(&mut state, &other, &alice_key, 1).unwrap();
+        assert_eq!(state.get_nonce(&other), Ok(2));
+    }
+
+    fn at(height: u64) -> Option<sdk::TxContext> {
+        Some(sdk::TxContext {
+            block_height: sdk::BlockHeight(height),
+            ..Default::default()
+        })
+    }
+
+    /// Verifies `pub_key` with the legacy message of `nonce`, signed by `key`
+    fn legacy_verify(
+        state: &mut IdentityContractState,
+        pub_key: &str,
+        key: &SigningKey,
+        nonce: u128,
+        tx_ctx: Option<sdk::TxContext>,
+    ) -> Result<String, String> {
+        let action = IdentityAction::VerifyIdentity {
+            nonce,
+            signature: personal_sign(key, &message::legacy_verify_message(nonce, &[])),
+            valid_until: None,
+        };
+        run(state, pub_key, action, &[], tx_ctx)
+    }
+
+    #[test]
+    fn legacy_messages_are_accepted_until_the_cutoff() {
+        let mut state = IdentityContractState::new(
+            DEFAULT_NETWORK_ID.to_string(),
+            LegacyMessages::Until(100),
+            DEFAULT_SIWE_CHAIN_ID,
+            None,
+        );
+        let key = signing_key(1);
+        let action = IdentityAction::RegisterIdentity {
+            signature: personal_sign(&key, message::LEGACY_REGISTRATION_MESSAGE),
+        };
+        assert!(run(&mut state, &address(&key), action.clone(), &[], at(101)).is_err());
+        run(&mut state, &address(&key), action, &[], at(100)).unwrap();
+        let pub_key = address(&key);
 
-fn sanitize_hex(hex_str: &str) -> &str {
-    hex_str.strip_prefix("0x").unwrap_or(hex_str)
+        assert!(legacy_verify(&mut state, &pub_key, &key, 0, None).is_err());
+        legacy_verify(&mut state, &pub_key, &key, 0, at(100)).unwrap();
+        assert!(legacy_verify(&mut state, &pub_key, &key, 1, at(101)).is_err());
+        verify(&mut state, &pub_key, &key, 1).unwrap();
+    }
+
+    #[test]
+    fn migrated_states_accept_legacy_messages_for_a_window() {
+        let mut identities = BTreeMap::new();
+        let key = signing_key(1);
+        let pub_key = address(&key);
+        identities.insert(
+            pub_key.clone(),
+            migration::AccountInfoV0 {
+                pub_key_hash: String::new(),
+                nonce: 0,
+            },
+        );
+        let mut state = IdentityContractState::from(migration::StateV0 { identities });
+        assert_eq!(state.legacy_messages, LegacyMessages::AfterMigration);
+
+        // The window starts with the first transaction executed by the new program
+        legacy_verify(&mut state, &pub_key, &key, 0, at(1_000)).unwrap();
+        let cutoff = 1_000 + LEGACY_MESSAGES_WINDOW;
+        assert_eq!(state.legacy_messages, LegacyMessages::Until(cutoff));
+        legacy_verify(&mut state, &pub_key, &key, 1, at(cutoff)).unwrap();
+        assert!(legacy_verify(&mut state, &pub_key, &key, 2, at(cutoff + 1)).is_err());
+        verify(&mut state, &pub_key, &key, 2).unwrap();
+    }
+
+    #[test]
+    fn nonces_are_split_into_independent_lanes() {
+        assert_eq!(lane_nonce(3, 7), (3 << 64) | 7);
+        assert_eq!(split_nonce(lane_nonce(3, 7)), (3, 7));
+
+        let mut state = IdentityContractState::default();
+        let key = signing_key(1);
+        let pub_key = register(&mut state, &key);
+
+        // Each lane only accepts increasing sequences, but may skip some
+        verify(&mut state, &pub_key, &key, lane_nonce(1, 5)).unwrap();
+        assert!(verify(&mut state, &pub_key, &key, lane_nonce(1, 5)).is_err());
+        assert!(verify(&mut state, &pub_key, &key, lane_nonce(1, 4)).is_err());
+        assert_eq!(state.get_lane_nonce(&pub_key, 1), Ok(lane_nonce(1, 6)));
+
+        // Other lanes are unaffected
+        assert_eq!(state.get_lane_nonce(&pub_key, 2), Ok(lane_nonce(2, 0)));
+        verify(&mut state, &pub_key, &key, lane_nonce(2, 0)).unwrap();
+        verify(&mut state, &pub_key, &key, lane_nonce(1, 6)).unwrap();
+        assert_eq!(state.get_lane_nonce(&pub_key, 2), Ok(lane_nonce(2, 1)));
+        assert_eq!(state.get_nonce(&pub_key), Ok(0));
+    }
+
+    #[test]
+    fn lane_0_is_the_sequential_nonce() {
+        let mut state = IdentityContractState::default();
+        let key = signing_key(1);
+        let pub_key = register(&mut state, &key);
+
+        verify(&mut state, &pub_key, &key, lane_nonce(0, 0)).unwrap();
+        verify(&mut state, &pub_key, &key, 1).unwrap();
+        assert_eq!(state.get_nonce(&pub_key), Ok(2));
+        assert_eq!(state.get_lane_nonce(&pub_key, 0), Ok(2));
+        assert!(verify(&mut state, &pub_key, &key, 1).is_err());
+
+        let info = state.get_identity_info(&pub_key).unwrap();
+        assert_eq!((info.nonce, info.lanes), (2, BTreeMap::new()));
+    }
+
+    #[test]
+    fn exhausted_lanes_reject_every_nonce() {
+        let mut state = IdentityContractState::default();
+        let key = signing_key(1);
+        let pub_key = register(&mut state, &key);
+
+        verify(&mut state, &pub_key, &key, lane_nonce(1, u64::MAX)).unwrap();
+        assert_eq!(
+            state.get_lane_nonce(&pub_key, 1),
+            Err("Nonce lane exhausted")
+        );
+        assert!(verify(&mut state, &pub_key, &key, lane_nonce(1, u64::MAX)).is_err());
+        // The next lane is still usable
+        verify(&mut state, &pub_key, &key, lane_nonce(2, 0)).unwrap();
+    }
+
+    #[test]
+    fn multisig_threshold_counts_distinct_signers() {
+        let mut state = IdentityContractState::default();
+        let keys: Vec<_> = (1..3).map(signing_key).collect();
+        let signers: Vec<String> = keys.iter().map(|key| register(&mut state, key)).collect();
+        let with_duplicate = vec![signers[0].clone(), signers[1].clone(), signers[0].clone()];
+
+        for threshold in [0, 3] {
+            let action = IdentityAction::RegisterMultisig {
+                signers: with_duplicate.clone(),
+                threshold,
+            };
+            let multisig = MultisigInfo::id(&with_duplicate, threshold);
+            assert_eq!(
+                run(&mut state, &multisig, action, &[], None),
+                Err(format!(
+                    "Invalid threshold {threshold} for 2 distinct signers"
+                ))
+            );
+        }
+
+        // The order and the duplicates of the signers do not change the multisig
+        let multisig = MultisigInfo::id(&with_duplicate, 2);
+        let reversed = vec![signers[1].clone(), signers[0].clone()];
+        assert_eq!(MultisigInfo::id(&reversed, 2), multisig);
+        let action = IdentityAction::RegisterMultisig {
+            signers: with_duplicate,
+            threshold: 2,
+        };
+        run(&mut state, &multisig, action, &[], None).unwrap();
+        let action = IdentityAction::RegisterMultisig {
+            signers: reversed,
+            threshold: 2,
+        };
+        assert_eq!(
+            run(&mut state, &multisig, action, &[], None),
+            Err("Identity already exists".to_string())
+        );
+    }
+
+    #[test]
+    fn multisig_signers_approve_once() {
+        let mut state = IdentityContractState::default();
+        let keys: Vec<_> = (1..3).map(signing_key).collect();
+        let signers: Vec<String> = keys.iter().map(|key| register(&mut state, key)).collect();
+        let multisig = MultisigInfo::id(&signers, 2);
+        let action = IdentityAction::RegisterMultisig {
+            signers: signers.clone(),
+            threshold: 2,
+        };
+        run(&mut state, &multisig, action, &[], None).unwrap();
+
+        let message = message::multisig_verify_message(
+            &contract_name(),
+            DEFAULT_NETWORK_ID,
+            &multisig,
+            0,
+            None,
+            &[],
+        );
+        let approve = |approvals: Vec<(String, &SigningKey)>| IdentityAction::VerifyMultisig {
+            nonce: 0,
+            signatures: approvals
+                .into_iter()
+                .map(|(signer, key)| (signer, personal_sign(key, &message)))
+                .collect(),
+            valid_until: None,
+        };
+
+        let action = approve(vec![(signers[0].clone(), &keys[0])]);
+        assert_eq!(
+            run(&mut state, &multisig, action, &[], None),
+            Err("Error verifying identity: 1 approvals out of the 2 required".to_string())
+        );
+        // Another spelling of the same signer is not a second approval
+        let checksummed = format!("0x{}", signers[0][2..].to_uppercase());
+        let action = approve(vec![
+            (signers[0].clone(), &keys[0]),
+            (checksummed.clone(), &keys[0]),
+        ]);
+        assert_eq!(
+            run(&mut state, &multisig, action, &[], None),
+            Err(format!(
+                "Error verifying identity: {checksummed} is not a signer of {multisig}"
+            ))
+        );
+
+        let action = approve(vec![
+            (signers[0].clone(), &keys[0]),
+            (signers[1].clone(), &keys[1]),
+        ]);
+        run(&mut state, &multisig, action.clone(), &[], None).unwrap();
+        assert_eq!(state.get_nonce(&multisig), Ok(1));
+        assert!(run(&mut state, &multisig, action, &[], None).is_err());
+    }
+
+    #[test]
+    fn deleted_identities_cannot_be_registered_again() {
+        let mut state = IdentityContractState::default();
+        let key = signing_key(1);
+        let pub_key = register(&mut state, &key);
+        verify(&mut state, &pub_key, &key, 0).unwrap();
+
+        let message = message::deletion_message(&contract_name(), DEFAULT_NETWORK_ID, &pub_key, 1);
+        let action = IdentityAction::DeleteIdentity {
+            nonce: 1,
+            signature: personal_sign(&key, &message),
+        };
+        run(&mut state, &pub_key, action, &[], None).unwrap();
+
+        let message = message::registration_message(&contract_name(), DEFAULT_NETWORK_ID);
+        let action = IdentityAction::RegisterIdentity {
+            signature: personal_sign(&key, &message),
+        };
+        assert_eq!(
+            run(&mut state, &pub_key, action, &[], None),
+            Err("Identity was deleted".to_string())
+        );
+        // The tombstone keeps the nonce, and the signatures made before stay unusable
+        assert_eq!(state.get_nonce(&pub_key), Ok(2));
+        assert_eq!(
+            verify(&mut state, &pub_key, &key, 2),
+            Err("Error verifying identity: Identity was deleted".to_string())
+        );
+    }
 }
@@ -0,0 +1,230 @@
+use sha3::{Digest, Keccak256};
+
+/// A node of the sparse Merkle tree
+pub type Hash = [u8; 32];
+
+/// Depth of the tree: leaves are placed at the 256 bits of their path
+const DEPTH: usize = 256;
+
+/// Hash of an empty subtree, whatever its depth
+pub const EMPTY: Hash = [0; 32];
+
+/// Path of a leaf, derived from its key
+pub fn path(key: &[u8]) -> Hash {
+    Keccak256::digest(key).into()
+}
+
+/// Hash of the leaf at `path` holding `value`
+pub fn leaf_hash(path: &Hash, value: &[u8]) -> Hash {
+    let mut hasher = Keccak256::new();
+    hasher.update([0]);
+    hasher.update(path);
+    hasher.update(value);
+    hasher.finalize().into()
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    // Empty subtrees hash to zero at every depth, so they need no precomputed table
+    if left == &EMPTY && right == &EMPTY {
+        return EMPTY;
+    }
+    let mut hasher = Keccak256::new();
+    hasher.update([1]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finalize().into()
+}
+
+fn bit(path: &Hash, depth: usize) -> bool {
+    (path[depth / 8] >> (7 - depth % 8)) & 1 == 1
+}
+
+/// Splits `items`, sorted by path and sharing their first `depth` bits, on the bit at `depth`
+fn split<T>(items: &[T], depth: usize, path_of: impl Fn(&T) -> &Hash) -> (&[T], &[T]) {
+    items.split_at(items.partition_point(|item| !bit(path_of(item), depth)))
+}
+
+/// Root of the tree holding `leaves`, `(path, leaf hash)` pairs sorted by path
+pub fn root(leaves: &[(Hash, Hash)]) -> Hash {
+    subtree(leaves, 0)
+}
+
+fn subtree(leaves: &[(Hash, Hash)], depth: usize) -> Hash {
+    match leaves {
+        [] => EMPTY,
+        [(_, leaf)] if depth == DEPTH => *leaf,
+        _ => {
+            let (left, right) = split(leaves, depth, |(path, _)| path);
+            node_hash(&subtree(left, depth + 1), &subtree(right, depth + 1))
+        }
+    }
+}
+
+/// Proof of the leaves at `paths` in the tree holding `leaves`, both sorted by path: the
+/// hashes of the subtrees holding none of them, in depth-first order, `None` when empty
+pub fn prove(leaves: &[(Hash, Hash)], paths: &[Hash]) -> Vec<Option<Hash>> {
+    let mut proof = Vec::new();
+    prove_subtree(leaves, paths, 0, &mut proof);
+    proof
+}
+
+fn prove_subtree(
+    leaves: &[(Hash, Hash)],
+    paths: &[Hash],
+    depth: usize,
+    proof: &mut Vec<Option<Hash>>,
+) {
+    if paths.is_empty() {
+        let hash = subtree(leaves, depth);
+        proof.push((hash != EMPTY).then_some(hash));
+        return;
+    }
+    if depth == DEPTH {
+        return;
+    }
+    let (leaves_left, leaves_right) = split(leaves, depth, |(path, _)| path);
+    let (paths_left, paths_right) = split(paths, depth, |path| path);
+    prove_subtree(leaves_left, paths_left, depth + 1, proof);
+    prove_subtree(leaves_right, paths_right, depth + 1, proof);
+}
+
+/// Root of a tree holding `leaves`, `(path, leaf hash)` pairs with an [`EMPTY`] hash for
+/// absent leaves, given the `proof` of their paths built by [`prove`]
+pub fn root_from_proof(leaves: &[(Hash, Hash)], proof: &[Option<Hash>]) -> Result<Hash, String> {
+    let mut leaves = leaves.to_vec();
+    leaves.sort();
+    if leaves.windows(2).any(|pair| pair[0].0 == pair[1].0) {
+        return Err("Duplicate leaf in Merkle proof".to_string());
+    }
+
+    let mut proof = proof.iter();
+    let root = root_from_subtree(&leaves, 0, &mut proof)?;
+    if proof.next().is_some() {
+        return Err("Unused nodes in Merkle proof".to_string());
+    }
+    Ok(root)
+}
+
+fn root_from_subtree<'a>(
+    leaves: &[(Hash, Hash)],
+    depth: usize,
+    proof: &mut impl Iterator<Item = &'a Option<Hash>>,
+) -> Result<Hash, String> {
+    match leaves {
+        [] => {
+            let node = proof.next().ok_or("Truncated Merkle proof")?;
+            Ok(node.unwrap_or(EMPTY))
+        }
+        [(_, leaf)] if depth == DEPTH => Ok(*leaf),
+        _ => {
+            let (left, right) = split(leaves, depth, |(path, _)| path);
+            let left = root_from_subtree(left, depth + 1, proof)?;
+            let right = root_from_subtree(right, depth + 1, proof)?;
+            Ok(node_hash(&left, &right))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `count` leaves, sorted by path
+    fn leaves(count: u8) -> Vec<(Hash, Hash)> {
+        let mut leaves: Vec<(Hash, Hash)> = (0..count)
+            .map(|key| {
+                let path = path(&[key]);
+                (path, leaf_hash(&path, &[key]))
+            })
+            .collect();
+        leaves.sort();
+        leaves
+    }
+
+    #[test]
+    fn proofs_round_trip() {
+        let leaves = leaves(16);
+        let root = root(&leaves);
+        let subsets: [&[usize]; 5] = [&[], &[0], &[3, 4], &[0, 7, 15], &[5, 6]];
+        for subset in subsets {
+            let proven: Vec<(Hash, Hash)> = subset.iter().map(|&i| leaves[i]).collect();
+            let paths: Vec<Hash> = proven.iter().map(|(path, _)| *path).collect();
+            let proof = prove(&leaves, &paths);
+            assert_eq!(root_from_proof(&proven, &proof), Ok(root));
+        }
+
+        let paths: Vec<Hash> = leaves.iter().map(|(path, _)| *path).collect();
+        assert_eq!(root_from_proof(&leaves, &prove(&leaves, &paths)), Ok(root));
+    }
+
+    #[test]
+    fn proves_the_empty_tree() {
+        assert_eq!(root(&[]), EMPTY);
+        let absent = path(b"absent");
+        let proof = prove(&[], &[absent]);
+        assert_eq!(root_from_proof(&[(absent, EMPTY)], &proof), Ok(EMPTY));
+    }
+
+    #[test]
+    fn proves_absent_leaves() {
+        let leaves = leaves(8);
+        let root = root(&leaves);
+        let absent = path(b"absent");
+        let proof = prove(&leaves, &[absent]);
+        assert_eq!(root_from_proof(&[(absent, EMPTY)], &proof), Ok(root));
+
+        // An absent leaf can then be inserted, and the new root computed from the same proof
+        let inserted = (absent, leaf_hash(&absent, b"inserted"));
+        let mut updated = leaves.clone();
+        updated.push(inserted);
+        updated.sort();
+        assert_eq!(
+            root_from_proof(&[inserted], &proof),
+            Ok(super::root(&updated))
+        );
+    }
+
+    #[test]
+    fn rejects_false_absence_claims() {
+        let leaves = leaves(8);
+        let root = root(&leaves);
+        let (present, _) = leaves[2];
+        let proof = prove(&leaves, &[present]);
+        assert_ne!(root_from_proof(&[(present, EMPTY)], &proof), Ok(root));
+
+        // Nor can the proof of an absent leaf vouch for the absence of a present one
+        let proof = prove(&leaves, &[path(b"absent")]);
+        assert_ne!(root_from_proof(&[(present, EMPTY)], &proof), Ok(root));
+    }
+
+    #[test]
+    fn rejects_forged_leaves() {
+        let leaves = leaves(8);
+        let root = root(&leaves);
+        let (path, _) = leaves[5];
+        let proof = prove(&leaves, &[path]);
+        let forged = (path, leaf_hash(&path, b"forged"));
+        assert_ne!(root_from_proof(&[forged], &proof), Ok(root));
+    }
+
+    #[test]
+    fn rejects_duplicate_leaves() {
+        let leaves = leaves(8);
+        let leaf = leaves[1];
+        let proof = prove(&leaves, &[leaf.0]);
+        assert!(root_from_proof(&[leaf, leaf], &proof).is_err());
+        // Even when they claim different values at the same path
+        assert!(root_from_proof(&[leaf, (leaf.0, EMPTY)], &proof).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_proofs() {
+        let leaves = leaves(8);
+        let leaf = leaves[3];
+        let mut proof = prove(&leaves, &[leaf.0]);
+        proof.push(None);
+        assert!(root_from_proof(&[leaf], &proof).is_err());
+        proof.truncate(proof.len() - 2);
+        assert!(root_from_proof(&[leaf], &proof).is_err());
+    }
+}
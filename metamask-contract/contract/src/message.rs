@@ -0,0 +1,325 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use sdk::{Blob, ContractName};
+use serde::{Deserialize, Serialize};
+use sha3::{Digest, Keccak256};
+use std::fmt;
+
+use crate::admin::AdminCommand;
+use crate::SessionKey;
+
+/// Domain tag prefixing every registration message
+pub const REGISTRATION_MESSAGE_TAG: &str = "hyle registration v2";
+
+/// Domain tag prefixing every `VerifyIdentity` message. Bump the version whenever
+/// the payload layout changes.
+pub const VERIFY_MESSAGE_TAG: &str = "hyle verify v3";
+
+/// Domain tag prefixing every `VerifyMultisig` message
+pub const MULTISIG_VERIFY_MESSAGE_TAG: &str = "hyle multisig verify v1";
+
+/// Domain tag prefixing every `RotateKey` message
+pub const ROTATION_MESSAGE_TAG: &str = "hyle rotate key v1";
+
+/// Domain tag prefixing every `DeleteIdentity` message
+pub const DELETION_MESSAGE_TAG: &str = "hyle delete identity v1";
+
+/// Domain tag prefixing every `SetGuardians` message
+pub const GUARDIANS_MESSAGE_TAG: &str = "hyle set guardians v1";
+
+/// Domain tag prefixing every guardian approval of an `InitiateRecovery`
+pub const RECOVERY_MESSAGE_TAG: &str = "hyle recovery v1";
+
+/// Domain tag prefixing every `CancelRecovery` message
+pub const CANCEL_RECOVERY_MESSAGE_TAG: &str = "hyle cancel recovery v1";
+
+/// Domain tag prefixing every `ClaimHandle` message
+pub const CLAIM_HANDLE_MESSAGE_TAG: &str = "hyle claim handle v1";
+
+/// Domain tag prefixing every `TransferHandle` message
+pub const TRANSFER_HANDLE_MESSAGE_TAG: &str = "hyle transfer handle v1";
+
+/// Domain tag prefixing every `ReleaseHandle` message
+pub const RELEASE_HANDLE_MESSAGE_TAG: &str = "hyle release handle v1";
+
+/// Domain tag prefixing every `SetRecord` message
+pub const RECORD_MESSAGE_TAG: &str = "hyle set record v1";
+
+/// Domain tag prefixing every `Admin` message
+pub const ADMIN_MESSAGE_TAG: &str = "hyle admin v1";
+
+/// Domain tag prefixing every `RegisterSessionKey` message
+pub const SESSION_KEY_MESSAGE_TAG: &str = "hyle session key v1";
+
+/// Domain tag prefixing every `VerifyIdentityWithSessionKey` message
+pub const SESSION_VERIFY_MESSAGE_TAG: &str = "hyle session verify v1";
+
+/// Registration message used before messages were bound to a contract and a network.
+/// Only accepted until the cutoff of [`crate::LegacyMessages`].
+pub const LEGACY_REGISTRATION_MESSAGE: &str = "hyle registration";
+
+/// Last block, or last timestamp in milliseconds, at which a signed authorization can be used
+#[derive(
+    BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq,
+)]
+pub enum Deadline {
+    BlockHeight(u64),
+    Timestamp(u128),
+}
+
+impl fmt::Display for Deadline {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Deadline::BlockHeight(height) => write!(f, "block {height}"),
+            Deadline::Timestamp(timestamp) => write!(f, "timestamp {timestamp}"),
+        }
+    }
+}
+
+/// Line of a verify message stating its deadline, empty without one so that messages
+/// without deadline are unchanged
+fn deadline_line(valid_until: Option<Deadline>) -> String {
+    valid_until
+        .map(|deadline| format!("\nvalid until: {deadline}"))
+        .unwrap_or_default()
+}
+
+/// Builds the `personal_sign` message registering an identity on `contract_name`,
+/// deployed on the network `network_id`.
+pub fn registration_message(contract_name: &ContractName, network_id: &str) -> String {
+    format!("{REGISTRATION_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}")
+}
+
+/// Builds the `personal_sign` message authorizing a transaction of `identity` with the given
+/// nonce and blobs, until the optional deadline. It names the identity, so that a key
+/// controlling several identities, after a rotation or a recovery, cannot replay it as
+/// another one.
+///
+/// This is the only place where this message is built: the contract, the host and
+/// clients must all go through it.
+pub fn verify_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    nonce: u128,
+    valid_until: Option<Deadline>,
+    blobs: &[Blob],
+) -> String {
+    format!(
+        "{VERIFY_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nnonce: {nonce}{}\nblobs: 0x{}",
+        deadline_line(valid_until),
+        hex::encode(blobs_digest(blobs))
+    )
+}
+
+/// Builds the `personal_sign` message each signer of the multisig `multisig_id` signs to
+/// approve a transaction. It names the multisig, so that an approval cannot be replayed
+/// as a signature of the signer's own identity.
+pub fn multisig_verify_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    multisig_id: &str,
+    nonce: u128,
+    valid_until: Option<Deadline>,
+    blobs: &[Blob],
+) -> String {
+    format!(
+        "{MULTISIG_VERIFY_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nmultisig: {multisig_id}\nnonce: {nonce}{}\nblobs: 0x{}",
+        deadline_line(valid_until),
+        hex::encode(blobs_digest(blobs))
+    )
+}
+
+/// Builds the message signed by both the current and the new key of `identity` to
+/// rotate its key to `new_key`
+pub fn rotation_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    new_key: &str,
+    nonce: u128,
+) -> String {
+    format!(
+        "{ROTATION_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nnew key: {new_key}\nnonce: {nonce}"
+    )
+}
+
+/// Builds the message the identity signs to permanently delete itself
+pub fn deletion_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    nonce: u128,
+) -> String {
+    format!(
+        "{DELETION_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nnonce: {nonce}"
+    )
+}
+
+/// Builds the message the identity signs to set its guardians
+pub fn guardians_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    guardians: &[String],
+    threshold: u32,
+    delay: u64,
+    nonce: u128,
+) -> String {
+    format!(
+        "{GUARDIANS_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nguardians: {}\nthreshold: {threshold}\ndelay: {delay} blocks\nnonce: {nonce}",
+        guardians.join(", ")
+    )
+}
+
+/// Builds the message each guardian signs to approve the recovery of `identity` to `new_key`
+pub fn recovery_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    new_key: &str,
+    nonce: u128,
+) -> String {
+    format!(
+        "{RECOVERY_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nnew key: {new_key}\nnonce: {nonce}"
+    )
+}
+
+/// Builds the message the identity signs to cancel its pending recovery to `new_key`
+pub fn cancel_recovery_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    new_key: &str,
+    nonce: u128,
+) -> String {
+    format!(
+        "{CANCEL_RECOVERY_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nnew key: {new_key}\nnonce: {nonce}"
+    )
+}
+
+/// Builds the message the identity signs to claim `handle`
+pub fn claim_handle_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    handle: &str,
+    nonce: u128,
+) -> String {
+    format!(
+        "{CLAIM_HANDLE_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nhandle: {handle}\nnonce: {nonce}"
+    )
+}
+
+/// Builds the message the identity signs to transfer its `handle` to the identity `to`
+pub fn transfer_handle_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    handle: &str,
+    to: &str,
+    nonce: u128,
+) -> String {
+    format!(
+        "{TRANSFER_HANDLE_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nhandle: {handle}\nto: {to}\nnonce: {nonce}"
+    )
+}
+
+/// Builds the message the identity signs to release its `handle`
+pub fn release_handle_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    handle: &str,
+    nonce: u128,
+) -> String {
+    format!(
+        "{RELEASE_HANDLE_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nhandle: {handle}\nnonce: {nonce}"
+    )
+}
+
+/// Builds the message the identity signs to set its text record `key` to `value`
+pub fn record_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    key: &str,
+    value: &str,
+    nonce: u128,
+) -> String {
+    format!(
+        "{RECORD_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nnonce: {nonce}\nkey: {key}\nvalue: {value}"
+    )
+}
+
+/// Builds the message the admin signs to apply `command`
+pub fn admin_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    admin: &str,
+    command: &AdminCommand,
+    nonce: u128,
+) -> String {
+    format!(
+        "{ADMIN_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nadmin: {admin}\ncommand: {command}\nnonce: {nonce}"
+    )
+}
+
+/// Builds the message the identity signs to delegate `key` with the scope of `session_key`
+pub fn session_key_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    key: &str,
+    session_key: &SessionKey,
+    nonce: u128,
+) -> String {
+    let allowed_contracts: Vec<&str> = session_key
+        .allowed_contracts
+        .iter()
+        .map(|contract| contract.0.as_str())
+        .collect();
+    format!(
+        "{SESSION_KEY_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nsession key: {key}\nexpires at block: {}\nallowed contracts: {}\nmax uses: {}\nnonce: {nonce}",
+        session_key.expires_at,
+        allowed_contracts.join(", "),
+        session_key.uses_left
+    )
+}
+
+/// Builds the `personal_sign` message a session key signs to authorize a transaction of
+/// `identity`. It names the identity, as a session key may be delegated by several.
+pub fn session_verify_message(
+    contract_name: &ContractName,
+    network_id: &str,
+    identity: &str,
+    nonce: u128,
+    valid_until: Option<Deadline>,
+    blobs: &[Blob],
+) -> String {
+    format!(
+        "{SESSION_VERIFY_MESSAGE_TAG}\ncontract: {contract_name}\nnetwork: {network_id}\nidentity: {identity}\nnonce: {nonce}{}\nblobs: 0x{}",
+        deadline_line(valid_until),
+        hex::encode(blobs_digest(blobs))
+    )
+}
+
+/// Builds the verify message signed before messages were bound to a contract and a network:
+/// `verify <nonce>` followed by the name and the Debug-formatted bytes of each blob.
+/// Only accepted until the cutoff of [`crate::LegacyMessages`].
+pub fn legacy_verify_message(nonce: u128, blobs: &[Blob]) -> String {
+    let blobs: Vec<String> = blobs
+        .iter()
+        .map(|blob| format!("{} {:?}", blob.contract_name, blob.data.0))
+        .collect();
+    format!("verify {nonce} {}", blobs.join(" "))
+}
+
+/// Keccak256 digest of the borsh-encoded list of `(contract_name, data)` of the blobs
+pub fn blobs_digest(blobs: &[Blob]) -> [u8; 32] {
+    let payload: Vec<(&str, &[u8])> = blobs
+        .iter()
+        .map(|blob| (blob.contract_name.0.as_str(), blob.data.0.as_slice()))
+        .collect();
+    let encoded = borsh::to_vec(&payload).expect("Failed to encode blobs");
+    Keccak256::digest(encoded).into()
+}
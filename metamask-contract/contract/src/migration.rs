@@ -0,0 +1,445 @@
+use borsh::{
+    io::{Error, ErrorKind, Read, Write},
+    BorshDeserialize, BorshSerialize,
+};
+use sdk::{ContractInput, HyleContract, RunResult};
+use std::collections::{BTreeMap, BTreeSet};
+
+use crate::{
+    admin::Admin,
+    commitment::{AccountLeaf, IdentityContractRoot},
+    keys::IdentityKey,
+    merkle::Hash,
+    recovery::{PendingRecovery, RecoveryConfig},
+    schemes::Scheme,
+    AccountInfo, IdentityContractState, LegacyMessages, MultisigInfo, SessionKey,
+    DEFAULT_NETWORK_ID, DEFAULT_SIWE_CHAIN_ID,
+};
+
+/// Prefix of the versioned state encodings. The unversioned state starts with the number of
+/// its identities, which never gets close to this value.
+const MAGIC: &[u8; 4] = b"hyid";
+
+/// Version of the state written by this program
+pub const STATE_VERSION: u8 = 3;
+
+/// Version of the account leaves written by this program
+pub const LEAF_VERSION: u8 = 3;
+
+/// The state of the contract as committed on-chain, in any of its versions. It is loaded by
+/// the zkvm and migrated to the current version by the first transaction it executes.
+///
+/// To change the layout of the root, add a variant with the next version, encode and decode
+/// it in [`BorshSerialize`] and [`BorshDeserialize`], and migrate the previous version to it
+/// in [`VersionedState::migrate`]. The leaves committed by the root are only migrated when a
+/// transaction touches them: to change their layout, add a variant to [`VersionedLeaf`] too.
+#[derive(Debug, Clone)]
+pub enum VersionedState {
+    /// Unversioned full state of the first deployments
+    V0(StateV0),
+    /// Merkle root of the state
+    V1(RootV1),
+    /// Merkle root of the state, with the admin controls
+    V2(RootV2),
+    /// Merkle root of the state, with the SIWE chain id
+    V3(IdentityContractRoot),
+}
+
+/// Layout of the state before it was versioned, holding all the identities
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct StateV0 {
+    pub identities: BTreeMap<String, AccountInfoV0>,
+}
+
+/// Layout of the committed root before the contract had an admin
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct RootV1 {
+    pub root: Hash,
+    pub network_id: String,
+    pub accept_legacy_messages: bool,
+}
+
+/// Layout of the committed root before the SIWE chain id
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct RootV2 {
+    pub root: Hash,
+    pub network_id: String,
+    pub accept_legacy_messages: bool,
+    pub admin: Option<AdminV2>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct AdminV2 {
+    pub identity: String,
+    pub registrations_paused: bool,
+    pub verifications_paused: bool,
+    pub frozen: BTreeSet<IdentityKey>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
+pub struct AccountInfoV0 {
+    /// Keccak256 of the identity key, superseded by [`AccountInfo::public_key`]
+    pub pub_key_hash: String,
+    pub nonce: u128,
+}
+
+impl VersionedState {
+    /// Upgrades the state to [`STATE_VERSION`]
+    pub fn migrate(&mut self) {
+        loop {
+            *self = match self {
+                VersionedState::V0(state) => {
+                    let identities = std::mem::take(&mut state.identities);
+                    let state = IdentityContractState::from(StateV0 { identities });
+                    VersionedState::V3(state.root())
+                }
+                VersionedState::V1(root) => VersionedState::V2(RootV2 {
+                    root: root.root,
+                    network_id: std::mem::take(&mut root.network_id),
+                    accept_legacy_messages: root.accept_legacy_messages,
+                    admin: None,
+                }),
+                VersionedState::V2(root) => VersionedState::V3(IdentityContractRoot {
+                    root: root.root,
+                    network_id: std::mem::take(&mut root.network_id),
+                    legacy_messages: if root.accept_legacy_messages {
+                        LegacyMessages::AfterMigration
+                    } else {
+                        LegacyMessages::Rejected
+                    },
+                    siwe_chain_id: DEFAULT_SIWE_CHAIN_ID,
+                    admin: root.admin.take().map(|admin| Admin {
+                        identity: IdentityKey::new(&admin.identity),
+                        registrations_paused: admin.registrations_paused,
+                        verifications_paused: admin.verifications_paused,
+                        frozen: admin.frozen,
+                    }),
+                }),
+                VersionedState::V3(_) => return,
+            };
+        }
+    }
+}
+
+impl HyleContract for VersionedState {
+    fn execute(&mut self, input: &ContractInput) -> RunResult {
+        self.migrate();
+        match self {
+            VersionedState::V3(root) => root.execute(input),
+            _ => Err("State was not migrated".to_string()),
+        }
+    }
+
+    fn commit(&self) -> sdk::StateCommitment {
+        sdk::StateCommitment(borsh::to_vec(self).expect("Failed to encode state"))
+    }
+}
+
+impl BorshSerialize for VersionedState {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        match self {
+            // Kept unversioned, so that its commitment is unchanged until it is migrated
+            VersionedState::V0(state) => state.serialize(writer),
+            VersionedState::V1(root) => {
+                writer.write_all(MAGIC)?;
+                1u8.serialize(writer)?;
+                root.serialize(writer)
+            }
+            VersionedState::V2(root) => {
+                writer.write_all(MAGIC)?;
+                2u8.serialize(writer)?;
+                root.serialize(writer)
+            }
+            VersionedState::V3(root) => {
+                writer.write_all(MAGIC)?;
+                3u8.serialize(writer)?;
+                root.serialize(writer)
+            }
+        }
+    }
+}
+
+impl BorshDeserialize for VersionedState {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        let mut bytes = Vec::new();
+        reader.read_to_end(&mut bytes)?;
+        let Some(versioned) = bytes.strip_prefix(MAGIC) else {
+            return borsh::from_slice(&bytes).map(VersionedState::V0);
+        };
+        match versioned.split_first() {
+            Some((1, root)) => borsh::from_slice(root).map(VersionedState::V1),
+            Some((2, root)) => borsh::from_slice(root).map(VersionedState::V2),
+            Some((3, root)) => borsh::from_slice(root).map(VersionedState::V3),
+            Some((version, _)) => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown state version {version}"),
+            )),
+            None => Err(Error::new(ErrorKind::InvalidData, "Missing state version")),
+        }
+    }
+}
+
+/// An account leaf of the state tree, in any of its versions. Leaves are hashed against the
+/// root in the encoding they were committed with, and written back in the current one by the
+/// transactions touching them, so that migrating the state does not rewrite the whole tree.
+///
+/// To change the layout of the leaves, add a variant with the next version, encode and
+/// decode it in [`BorshSerialize`] and [`BorshDeserialize`], and migrate the previous version
+/// to it in [`VersionedLeaf::migrate`].
+#[derive(serde::Serialize, serde::Deserialize, Debug, Clone, Eq, PartialEq)]
+pub enum VersionedLeaf {
+    /// Unversioned leaves of the V1 and V2 states
+    V2(AccountLeafV2),
+    /// Leaves holding identity keys in binary form, and the nonce lanes of multisigs
+    V3(AccountLeaf),
+}
+
+/// Layout of the account leaves of the V1 and V2 states, holding identity keys as strings
+#[derive(
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Clone,
+    Eq,
+    PartialEq,
+)]
+pub struct AccountLeafV2 {
+    pub info: Option<AccountInfoV2>,
+    pub multisig: Option<MultisigInfoV2>,
+    pub session_keys: BTreeMap<IdentityKey, SessionKey>,
+    pub recovery: Option<RecoveryConfigV2>,
+    pub records: BTreeMap<String, String>,
+}
+
+#[derive(
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Clone,
+    Eq,
+    PartialEq,
+)]
+pub struct AccountInfoV2 {
+    #[serde(with = "crate::keys::hex_public_key")]
+    pub public_key: Option<[u8; 33]>,
+    pub nonce: u128,
+    pub lanes: BTreeMap<u64, u128>,
+    pub scheme: Scheme,
+    pub authority: Option<String>,
+    pub deleted: bool,
+    pub handle: Option<String>,
+}
+
+#[derive(
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Clone,
+    Eq,
+    PartialEq,
+)]
+pub struct MultisigInfoV2 {
+    pub signers: Vec<String>,
+    pub threshold: u32,
+    pub nonce: u128,
+}
+
+#[derive(
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Clone,
+    Eq,
+    PartialEq,
+)]
+pub struct RecoveryConfigV2 {
+    pub guardians: Vec<String>,
+    pub threshold: u32,
+    pub delay: u64,
+    pub pending: Option<PendingRecoveryV2>,
+}
+
+#[derive(
+    BorshSerialize,
+    BorshDeserialize,
+    serde::Serialize,
+    serde::Deserialize,
+    Debug,
+    Clone,
+    Eq,
+    PartialEq,
+)]
+pub struct PendingRecoveryV2 {
+    pub new_key: String,
+    pub executable_at: u64,
+}
+
+impl VersionedLeaf {
+    /// Upgrades the leaf to [`LEAF_VERSION`]
+    pub fn migrate(self) -> AccountLeaf {
+        let keys = |keys: Vec<String>| keys.iter().map(|key| IdentityKey::new(key)).collect();
+        match self {
+            VersionedLeaf::V2(leaf) => AccountLeaf {
+                info: leaf.info.map(|info| AccountInfo {
+                    public_key: info.public_key,
+                    nonce: info.nonce,
+                    lanes: info.lanes,
+                    scheme: info.scheme,
+                    authority: info.authority.as_deref().map(IdentityKey::new),
+                    deleted: info.deleted,
+                    handle: info.handle,
+                }),
+                multisig: leaf.multisig.map(|multisig| MultisigInfo {
+                    signers: keys(multisig.signers),
+                    threshold: multisig.threshold,
+                    nonce: multisig.nonce,
+                    lanes: BTreeMap::new(),
+                }),
+                session_keys: leaf.session_keys,
+                recovery: leaf.recovery.map(|recovery| RecoveryConfig {
+                    guardians: keys(recovery.guardians),
+                    threshold: recovery.threshold,
+                    delay: recovery.delay,
+                    pending: recovery.pending.map(|pending| PendingRecovery {
+                        new_key: IdentityKey::new(&pending.new_key),
+                        executable_at: pending.executable_at,
+                    }),
+                }),
+                records: leaf.records,
+            },
+            VersionedLeaf::V3(leaf) => leaf,
+        }
+    }
+}
+
+impl BorshSerialize for VersionedLeaf {
+    fn serialize<W: Write>(&self, writer: &mut W) -> Result<(), Error> {
+        match self {
+            // Kept unversioned, so that its hash is unchanged until it is migrated
+            VersionedLeaf::V2(leaf) => leaf.serialize(writer),
+            VersionedLeaf::V3(leaf) => {
+                3u8.serialize(writer)?;
+                leaf.serialize(writer)
+            }
+        }
+    }
+}
+
+impl BorshDeserialize for VersionedLeaf {
+    fn deserialize_reader<R: Read>(reader: &mut R) -> Result<Self, Error> {
+        // Unversioned leaves start with the tag of their optional `info`, 0 or 1
+        let version = u8::deserialize_reader(reader)?;
+        match version {
+            0 | 1 => AccountLeafV2::deserialize_reader(&mut [version].as_slice().chain(reader))
+                .map(VersionedLeaf::V2),
+            3 => AccountLeaf::deserialize_reader(reader).map(VersionedLeaf::V3),
+            version => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("Unknown leaf version {version}"),
+            )),
+        }
+    }
+}
+
+impl From<StateV0> for IdentityContractState {
+    fn from(state: StateV0) -> Self {
+        // Those identities only ever signed the legacy registration and verify messages (see
+        // `message::legacy_verify_message`), which were not bound to a network yet: they are
+        // accepted for a while, so that their wallets can switch to the current ones
+        let mut migrated = IdentityContractState::new(
+            DEFAULT_NETWORK_ID.to_string(),
+            LegacyMessages::AfterMigration,
+            DEFAULT_SIWE_CHAIN_ID,
+            None,
+        );
+        for (pub_key, info) in state.identities {
+            migrated.identities.insert(
+                IdentityKey::new(&pub_key),
+                AccountInfo {
+                    // Recovered at the next verification
+                    public_key: None,
+                    nonce: info.nonce,
+                    lanes: BTreeMap::new(),
+                    scheme: Scheme::Ethereum,
+                    authority: None,
+                    deleted: false,
+                    handle: None,
+                },
+            );
+        }
+        migrated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_v2_roots() {
+        let admin = "0x1111111111111111111111111111111111111111";
+        let frozen = BTreeSet::from([IdentityKey::new(
+            "0x2222222222222222222222222222222222222222",
+        )]);
+        let v2 = VersionedState::V2(RootV2 {
+            root: [7; 32],
+            network_id: "hyle-testnet".to_string(),
+            accept_legacy_messages: false,
+            admin: Some(AdminV2 {
+                identity: admin.to_string(),
+                registrations_paused: true,
+                verifications_paused: false,
+                frozen: frozen.clone(),
+            }),
+        });
+
+        let mut state: VersionedState = borsh::from_slice(&borsh::to_vec(&v2).unwrap()).unwrap();
+        state.migrate();
+        let VersionedState::V3(root) = state else {
+            panic!("State was not migrated");
+        };
+        assert_eq!(root.root, [7; 32]);
+        assert_eq!(root.network_id, "hyle-testnet");
+        assert_eq!(root.legacy_messages, LegacyMessages::Rejected);
+        assert_eq!(root.siwe_chain_id, DEFAULT_SIWE_CHAIN_ID);
+        assert_eq!(
+            root.admin,
+            Some(Admin {
+                identity: IdentityKey::new(admin),
+                registrations_paused: true,
+                verifications_paused: false,
+                frozen,
+            })
+        );
+    }
+
+    #[test]
+    fn decodes_versioned_leaves() {
+        let legacy = VersionedLeaf::V2(AccountLeafV2 {
+            info: None,
+            multisig: Some(MultisigInfoV2 {
+                signers: vec!["0x1111111111111111111111111111111111111111".to_string()],
+                threshold: 1,
+                nonce: 4,
+            }),
+            session_keys: BTreeMap::new(),
+            recovery: None,
+            records: BTreeMap::new(),
+        });
+        let current = VersionedLeaf::V3(legacy.clone().migrate());
+
+        for leaf in [legacy, current] {
+            let encoded = borsh::to_vec(&leaf).unwrap();
+            assert_eq!(borsh::from_slice::<VersionedLeaf>(&encoded).unwrap(), leaf);
+        }
+        assert!(borsh::from_slice::<VersionedLeaf>(&[2]).is_err());
+    }
+}
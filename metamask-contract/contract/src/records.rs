@@ -0,0 +1,78 @@
+use sdk::ContractName;
+use std::collections::BTreeMap;
+
+use crate::{active_identity, keys::IdentityKey, message, IdentityContractState};
+
+/// Maximum length in bytes of a record key
+pub const MAX_RECORD_KEY_LEN: usize = 64;
+/// Maximum length in bytes of a record value
+pub const MAX_RECORD_VALUE_LEN: usize = 512;
+/// Maximum number of records of an identity
+pub const MAX_RECORDS: usize = 32;
+
+impl IdentityContractState {
+    /// Text records of the identity `account`, by key
+    pub fn records_of(&self, account: &str) -> Option<&BTreeMap<String, String>> {
+        self.records.get(&IdentityKey::new(account))
+    }
+
+    /// Sets the text record `key` of the identity, or removes it when `value` is empty
+    pub(crate) fn set_record(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        key: String,
+        value: String,
+        contract_name: &ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        if key.is_empty() || key.len() > MAX_RECORD_KEY_LEN || key.contains('\n') {
+            return Err(format!("Invalid record key {key}"));
+        }
+        if value.len() > MAX_RECORD_VALUE_LEN {
+            return Err(format!(
+                "Record values are limited to {MAX_RECORD_VALUE_LEN} bytes"
+            ));
+        }
+
+        let identity = IdentityKey::new(pub_key);
+        let records = self.records.get(&identity);
+        let count = records.map_or(0, BTreeMap::len);
+        let is_new = !records.is_some_and(|records| records.contains_key(&key));
+        if !value.is_empty() && is_new && count >= MAX_RECORDS {
+            return Err(format!("An identity holds at most {MAX_RECORDS} records"));
+        }
+
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+        let message = message::record_message(
+            contract_name,
+            &self.network_id,
+            pub_key,
+            &key,
+            &value,
+            nonce,
+        );
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+        stored_info.use_nonce(nonce);
+
+        if value.is_empty() {
+            if let Some(records) = self.records.get_mut(&identity) {
+                records.remove(&key);
+                if records.is_empty() {
+                    self.records.remove(&identity);
+                }
+            }
+            return Ok(format!("Record {key} removed for {pub_key}"));
+        }
+        self.records
+            .entry(identity)
+            .or_default()
+            .insert(key.clone(), value);
+
+        Ok(format!("Record {key} set for {pub_key}"))
+    }
+}
@@ -0,0 +1,419 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use sdk::{ContractName, TxContext};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use crate::{active_identity, keys::IdentityKey, message, IdentityContractState};
+
+/// Guardians allowed to jointly recover an identity
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct RecoveryConfig {
+    /// Identities of the guardians, sorted
+    pub guardians: Vec<IdentityKey>,
+    /// Number of distinct guardians required to initiate a recovery
+    pub threshold: u32,
+    /// Number of blocks during which the owner can cancel an initiated recovery
+    pub delay: u64,
+    pub pending: Option<PendingRecovery>,
+}
+
+/// A recovery initiated by the guardians, waiting for its delay to elapse
+#[derive(BorshSerialize, BorshDeserialize, Serialize, Deserialize, Debug, Clone, Eq, PartialEq)]
+pub struct PendingRecovery {
+    pub new_key: IdentityKey,
+    /// Block height from which the recovery can be completed
+    pub executable_at: u64,
+}
+
+impl IdentityContractState {
+    /// Replaces the guardians of the identity. An empty list disables recovery.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn set_guardians(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        mut guardians: Vec<String>,
+        threshold: u32,
+        delay: u64,
+        contract_name: &ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        guardians.sort();
+        guardians.dedup();
+        if !guardians.is_empty() && (threshold == 0 || threshold as usize > guardians.len()) {
+            return Err(format!(
+                "Invalid threshold {threshold} for {} distinct guardians",
+                guardians.len()
+            ));
+        }
+        for guardian in &guardians {
+            if guardian == pub_key {
+                return Err("An identity cannot be its own guardian".to_string());
+            }
+            match self.identities.get(&IdentityKey::new(guardian)) {
+                Some(info) if !info.deleted => {}
+                _ => return Err(format!("Guardian {guardian} is not registered")),
+            }
+        }
+
+        let message = message::guardians_message(
+            contract_name,
+            &self.network_id,
+            pub_key,
+            &guardians,
+            threshold,
+            delay,
+            nonce,
+        );
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+        stored_info.use_nonce(nonce);
+
+        if guardians.is_empty() {
+            self.recoveries.remove(&IdentityKey::new(pub_key));
+            return Ok(format!("Guardians removed for {pub_key}"));
+        }
+        let mut guardians: Vec<IdentityKey> = guardians
+            .iter()
+            .map(|guardian| IdentityKey::new(guardian))
+            .collect();
+        guardians.sort();
+        self.recoveries.insert(
+            IdentityKey::new(pub_key),
+            RecoveryConfig {
+                guardians,
+                threshold,
+                delay,
+                pending: None,
+            },
+        );
+
+        Ok(format!("Guardians set for {pub_key}"))
+    }
+
+    /// Starts the recovery of the identity to `new_key`, approved by at least `threshold`
+    /// guardians. It can be completed once the delay has elapsed.
+    pub(crate) fn initiate_recovery(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        new_key: String,
+        contract_name: &ContractName,
+        signatures: &BTreeMap<String, String>,
+        tx_ctx: Option<&TxContext>,
+    ) -> Result<String, String> {
+        let tx_ctx = tx_ctx.ok_or("Recoveries require the transaction context")?;
+        let key = IdentityKey::new(pub_key);
+        let config = self
+            .recoveries
+            .get_mut(&key)
+            .ok_or(format!("No guardians for {pub_key}"))?;
+        if config.pending.is_some() {
+            return Err(format!("A recovery of {pub_key} is already pending"));
+        }
+
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+        stored_info
+            .scheme
+            .verifier()
+            .validate_key(&new_key)
+            .map_err(|err| format!("Invalid new key {new_key}: {err}"))?;
+
+        let message =
+            message::recovery_message(contract_name, &self.network_id, pub_key, &new_key, nonce);
+
+        // Signatures are keyed by guardian, so each guardian approves at most once
+        let mut approvals = 0;
+        for (guardian, signature) in signatures {
+            if config
+                .guardians
+                .binary_search(&IdentityKey::new(guardian))
+                .is_err()
+            {
+                return Err(format!("{guardian} is not a guardian of {pub_key}"));
+            }
+            // The approvals of frozen guardians are not counted
+            if self
+                .admin
+                .as_ref()
+                .is_some_and(|admin| admin.is_frozen(guardian))
+            {
+                continue;
+            }
+            let guardian_info = self
+                .identities
+                .get(&IdentityKey::new(guardian))
+                .filter(|info| !info.deleted)
+                .ok_or(format!("Guardian {guardian} is no longer registered"))?;
+            guardian_info
+                .scheme
+                .verifier()
+                .verify(&guardian_info.authority(guardian), signature, &message)
+                .map_err(|err| {
+                    format!("Invalid signature of {guardian} for message {message}: {err}")
+                })?;
+            approvals += 1;
+        }
+        if approvals < config.threshold {
+            return Err(format!(
+                "{approvals} approvals out of the {} required",
+                config.threshold
+            ));
+        }
+
+        let executable_at = tx_ctx.block_height.0.saturating_add(config.delay);
+        config.pending = Some(PendingRecovery {
+            new_key: IdentityKey::new(&new_key),
+            executable_at,
+        });
+        // Approvals are bound to the nonce, so they cannot be replayed after a cancellation
+        if let Some(stored_info) = self.identities.get_mut(&key) {
+            stored_info.use_nonce(nonce);
+        }
+
+        Ok(format!(
+            "Recovery of {pub_key} initiated, executable at block {executable_at}"
+        ))
+    }
+
+    /// Cancels the pending recovery of the identity, signed by its current key
+    pub(crate) fn cancel_recovery(
+        &mut self,
+        pub_key: &str,
+        nonce: u128,
+        contract_name: &ContractName,
+        signature: &str,
+    ) -> Result<String, String> {
+        let pending = self
+            .recoveries
+            .get(&IdentityKey::new(pub_key))
+            .and_then(|config| config.pending.as_ref())
+            .ok_or(format!("No pending recovery for {pub_key}"))?;
+
+        let stored_info = active_identity(&mut self.identities, pub_key, nonce)?;
+        let message = message::cancel_recovery_message(
+            contract_name,
+            &self.network_id,
+            pub_key,
+            &pending.new_key.to_string(),
+            nonce,
+        );
+        stored_info
+            .scheme
+            .verifier()
+            .verify(&stored_info.authority(pub_key), signature, &message)
+            .map_err(|err| format!("Invalid signature for message {message}: {err}"))?;
+        stored_info.use_nonce(nonce);
+
+        if let Some(config) = self.recoveries.get_mut(&IdentityKey::new(pub_key)) {
+            config.pending = None;
+        }
+
+        Ok(format!("Recovery of {pub_key} cancelled"))
+    }
+
+    /// Hands the identity over to the key of its pending recovery, once the delay elapsed.
+    /// Anyone can submit it.
+    pub(crate) fn complete_recovery(
+        &mut self,
+        pub_key: &str,
+        tx_ctx: Option<&TxContext>,
+    ) -> Result<String, String> {
+        let tx_ctx = tx_ctx.ok_or("Recoveries require the transaction context")?;
+        let key = IdentityKey::new(pub_key);
+        let config = self
+            .recoveries
+            .get_mut(&key)
+            .ok_or(format!("No pending recovery for {pub_key}"))?;
+        let pending = config
+            .pending
+            .as_ref()
+            .ok_or(format!("No pending recovery for {pub_key}"))?;
+        if tx_ctx.block_height.0 < pending.executable_at {
+            return Err(format!(
+                "Recovery of {pub_key} cannot be completed before block {}",
+                pending.executable_at
+            ));
+        }
+
+        let stored_info = self
+            .identities
+            .get_mut(&key)
+            .filter(|info| !info.deleted)
+            .ok_or("Identity not found")?;
+        let new_key = config.pending.take().map(|pending| pending.new_key);
+        stored_info.authority = new_key.filter(|new_key| *new_key != key);
+        // Recovered from the first signature of the new key
+        stored_info.public_key = None;
+        // Session keys may have been delegated by the lost key
+        self.session_keys.remove(&key);
+
+        Ok(format!("Identity {pub_key} recovered"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::actions::IdentityAction;
+    use crate::tests::{contract_name, personal_sign, register, run, signing_key, verify};
+    use crate::DEFAULT_NETWORK_ID;
+    use k256::ecdsa::SigningKey;
+    use sdk::BlockHeight;
+
+    struct Setup {
+        state: IdentityContractState,
+        owner: String,
+        owner_key: SigningKey,
+        guardian_keys: Vec<SigningKey>,
+    }
+
+    /// An identity recoverable by 2 of 3 guardians, after 10 blocks
+    fn setup() -> Setup {
+        let mut state = IdentityContractState::default();
+        let owner_key = signing_key(1);
+        let owner = register(&mut state, &owner_key);
+        let guardian_keys: Vec<SigningKey> = (2..5).map(signing_key).collect();
+        let mut guardians: Vec<String> = guardian_keys
+            .iter()
+            .map(|key| register(&mut state, key))
+            .collect();
+        guardians.sort();
+
+        let message = message::guardians_message(
+            &contract_name(),
+            DEFAULT_NETWORK_ID,
+            &owner,
+            &guardians,
+            2,
+            10,
+            0,
+        );
+        let action = IdentityAction::SetGuardians {
+            nonce: 0,
+            guardians,
+            threshold: 2,
+            delay: 10,
+            signature: personal_sign(&owner_key, &message),
+        };
+        run(&mut state, &owner, action, &[], None).unwrap();
+        Setup {
+            state,
+            owner,
+            owner_key,
+            guardian_keys,
+        }
+    }
+
+    fn at(height: u64) -> Option<TxContext> {
+        Some(TxContext {
+            block_height: BlockHeight(height),
+            ..Default::default()
+        })
+    }
+
+    fn initiate(
+        setup: &mut Setup,
+        new_key: &str,
+        nonce: u128,
+        guardians: &[usize],
+        tx_ctx: Option<TxContext>,
+    ) -> Result<String, String> {
+        let message = message::recovery_message(
+            &contract_name(),
+            DEFAULT_NETWORK_ID,
+            &setup.owner,
+            new_key,
+            nonce,
+        );
+        let signatures = guardians
+            .iter()
+            .map(|&i| {
+                let key = &setup.guardian_keys[i];
+                (crate::tests::address(key), personal_sign(key, &message))
+            })
+            .collect();
+        let action = IdentityAction::InitiateRecovery {
+            nonce,
+            new_key: new_key.to_string(),
+            signatures,
+        };
+        run(&mut setup.state, &setup.owner, action, &[], tx_ctx)
+    }
+
+    fn complete(setup: &mut Setup, tx_ctx: Option<TxContext>) -> Result<String, String> {
+        run(
+            &mut setup.state,
+            &setup.owner,
+            IdentityAction::CompleteRecovery,
+            &[],
+            tx_ctx,
+        )
+    }
+
+    #[test]
+    fn recovers_after_the_delay_unless_cancelled() {
+        let mut setup = setup();
+        let new_key = signing_key(9);
+        let new_address = crate::tests::address(&new_key);
+
+        assert!(initiate(&mut setup, &new_address, 1, &[0, 1], None).is_err());
+        assert!(initiate(&mut setup, &new_address, 1, &[0], at(100)).is_err());
+        initiate(&mut setup, &new_address, 1, &[0, 2], at(100)).unwrap();
+        assert!(initiate(&mut setup, &new_address, 2, &[0, 1], at(101)).is_err());
+
+        // The owner can cancel until the delay elapsed
+        assert!(complete(&mut setup, at(109)).is_err());
+        let message = message::cancel_recovery_message(
+            &contract_name(),
+            DEFAULT_NETWORK_ID,
+            &setup.owner,
+            &new_address,
+            2,
+        );
+        let action = IdentityAction::CancelRecovery {
+            nonce: 2,
+            signature: personal_sign(&setup.owner_key, &message),
+        };
+        run(&mut setup.state, &setup.owner, action, &[], None).unwrap();
+        assert!(complete(&mut setup, at(200)).is_err());
+
+        // Approvals are bound to their nonce
+        assert!(initiate(&mut setup, &new_address, 1, &[0, 2], at(200)).is_err());
+        initiate(&mut setup, &new_address, 3, &[1, 2], at(200)).unwrap();
+        complete(&mut setup, at(210)).unwrap();
+
+        let owner = setup.owner.clone();
+        let info = setup.state.get_identity_info(&owner).unwrap();
+        assert_eq!(info.authority, Some(IdentityKey::new(&new_address)));
+        assert!(verify(&mut setup.state, &owner, &setup.owner_key, 4).is_err());
+        verify(&mut setup.state, &owner, &new_key, 4).unwrap();
+        assert!(complete(&mut setup, at(300)).is_err());
+    }
+
+    #[test]
+    fn recovered_identities_do_not_share_signatures() {
+        let mut setup = setup();
+        // The identity is recovered to the key of another registered identity
+        let alice_key = signing_key(9);
+        let alice = register(&mut setup.state, &alice_key);
+        initiate(&mut setup, &alice, 1, &[0, 1], at(100)).unwrap();
+        complete(&mut setup, at(110)).unwrap();
+
+        let owner = setup.owner.clone();
+        let message =
+            message::verify_message(&contract_name(), DEFAULT_NETWORK_ID, &alice, 2, None, &[]);
+        let action = IdentityAction::VerifyIdentity {
+            nonce: 2,
+            signature: personal_sign(&alice_key, &message),
+            valid_until: None,
+        };
+        assert!(run(&mut setup.state, &owner, action.clone(), &[], None).is_err());
+        run(&mut setup.state, &alice, action, &[], None).unwrap();
+        verify(&mut setup.state, &owner, &alice_key, 2).unwrap();
+    }
+}
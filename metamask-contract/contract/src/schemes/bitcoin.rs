@@ -0,0 +1,373 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use bech32::{hrp, segwit};
+use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use k256::schnorr;
+use ripemd::Ripemd160;
+use sha2::{Digest, Sha256};
+
+use super::{SignatureError, SignatureScheme};
+
+/// Bitcoin wallets: the identity is a P2PKH, P2SH-P2WPKH, P2WPKH or P2TR address (mainnet,
+/// testnet or regtest). Messages are signed with the "Bitcoin Signed Message" format of
+/// BIP-137 for legacy and segwit v0 addresses, with the header byte of the address type,
+/// and with BIP-322 simple signatures for taproot addresses. Signatures are base64-encoded,
+/// as output by wallets.
+pub struct Bitcoin;
+
+enum Address {
+    P2pkh([u8; 20]),
+    P2shP2wpkh([u8; 20]),
+    P2wpkh([u8; 20]),
+    P2tr([u8; 32]),
+}
+
+impl SignatureScheme for Bitcoin {
+    fn validate_key(&self, key: &str) -> Result<(), SignatureError> {
+        parse_address(key).map(|_| ())
+    }
+
+    fn verify(&self, key: &str, signature: &str, message: &str) -> Result<(), SignatureError> {
+        let address = parse_address(key)?;
+        let signature = STANDARD
+            .decode(signature)
+            .map_err(|_| SignatureError::BadEncoding)?;
+
+        match address {
+            Address::P2tr(output_key) => verify_bip322_p2tr(&output_key, &signature, message),
+            address => verify_bip137(&address, &signature, message),
+        }
+    }
+}
+
+fn parse_address(address: &str) -> Result<Address, SignatureError> {
+    if let Ok((hrp, version, program)) = segwit::decode(address) {
+        // Only the lowercase form, so that an address maps to a single identity
+        if ![hrp::BC, hrp::TB, hrp::BCRT].contains(&hrp) || address != address.to_lowercase() {
+            return Err(SignatureError::BadKey);
+        }
+        return match (version, program.len()) {
+            (segwit::VERSION_0, 20) => Ok(Address::P2wpkh(program.try_into().unwrap())),
+            (segwit::VERSION_1, 32) => Ok(Address::P2tr(program.try_into().unwrap())),
+            _ => Err(SignatureError::BadKey),
+        };
+    }
+
+    let bytes = bs58::decode(address)
+        .with_check(None)
+        .into_vec()
+        .map_err(|_| SignatureError::BadKey)?;
+    let (version, hash) = bytes.split_first().ok_or(SignatureError::BadKey)?;
+    let hash: [u8; 20] = hash.try_into().map_err(|_| SignatureError::BadKey)?;
+    match version {
+        0x00 | 0x6f => Ok(Address::P2pkh(hash)),
+        0x05 | 0xc4 => Ok(Address::P2shP2wpkh(hash)),
+        _ => Err(SignatureError::BadKey),
+    }
+}
+
+/// Verifies a 65-byte `header || r || s` BIP-137 signature, recovering the public key and
+/// matching it against the address
+fn verify_bip137(address: &Address, signature: &[u8], message: &str) -> Result<(), SignatureError> {
+    if signature.len() != 65 {
+        return Err(SignatureError::BadLength(signature.len()));
+    }
+
+    // 27-30: P2PKH uncompressed, 31-34: P2PKH compressed, 35-38: P2SH-P2WPKH, 39-42: P2WPKH.
+    // The header must match the address type, so that a signature made for one address of a
+    // key is not accepted for its other addresses.
+    let header = signature[0];
+    let compressed = match (address, header) {
+        (Address::P2pkh(_), 27..=30) => false,
+        (Address::P2pkh(_), 31..=34)
+        | (Address::P2shP2wpkh(_), 35..=38)
+        | (Address::P2wpkh(_), 39..=42) => true,
+        _ => return Err(SignatureError::BadRecoveryId(u64::from(header))),
+    };
+    let recovery_id = (header - 27) % 4;
+    let recovery_id = RecoveryId::from_byte(recovery_id)
+        .ok_or(SignatureError::BadRecoveryId(u64::from(header)))?;
+
+    let ecdsa_signature =
+        Signature::from_slice(&signature[1..]).map_err(|_| SignatureError::RecoveryFailed)?;
+    if ecdsa_signature.normalize_s().is_some() {
+        return Err(SignatureError::HighS);
+    }
+
+    let recovered_key = VerifyingKey::recover_from_prehash(
+        &signed_message_hash(message),
+        &ecdsa_signature,
+        recovery_id,
+    )
+    .map_err(|_| SignatureError::RecoveryFailed)?;
+    let key_hash = hash160(recovered_key.to_encoded_point(compressed).as_bytes());
+
+    let matches = match address {
+        Address::P2pkh(hash) => key_hash == *hash,
+        Address::P2wpkh(hash) => key_hash == *hash,
+        Address::P2shP2wpkh(hash) => {
+            let mut redeem_script = vec![0x00, 0x14];
+            redeem_script.extend_from_slice(&key_hash);
+            hash160(&redeem_script) == *hash
+        }
+        Address::P2tr(_) => false,
+    };
+
+    if !matches {
+        return Err(SignatureError::InvalidSignature);
+    }
+    Ok(())
+}
+
+/// Verifies a BIP-322 simple signature (a serialized witness holding a single key path
+/// Schnorr signature) for a taproot address
+fn verify_bip322_p2tr(
+    output_key: &[u8; 32],
+    witness: &[u8],
+    message: &str,
+) -> Result<(), SignatureError> {
+    let mut reader = Reader(witness);
+    let items = reader.compact_size()?;
+    let signature = reader.bytes()?;
+    if items != 1 || !reader.0.is_empty() {
+        return Err(SignatureError::BadEncoding);
+    }
+
+    // 64 bytes for SIGHASH_DEFAULT, or 65 bytes with an explicit SIGHASH_ALL
+    let (signature, hash_type) = match signature.len() {
+        64 => (signature, 0x00),
+        65 if signature[64] == 0x01 => (&signature[..64], 0x01),
+        65 => return Err(SignatureError::BadRecoveryId(u64::from(signature[64]))),
+        len => return Err(SignatureError::BadLength(len)),
+    };
+    let signature =
+        schnorr::Signature::try_from(signature).map_err(|_| SignatureError::InvalidSignature)?;
+    let verifying_key =
+        schnorr::VerifyingKey::from_bytes(output_key).map_err(|_| SignatureError::BadKey)?;
+
+    let mut script_pubkey = vec![0x51, 0x20]; // OP_1 PUSH32
+    script_pubkey.extend_from_slice(output_key);
+
+    let to_spend_txid = to_spend_txid(&script_pubkey, message);
+    let sighash = to_sign_sighash(&to_spend_txid, &script_pubkey, hash_type);
+
+    verifying_key
+        .verify_raw(&sighash, &signature)
+        .map_err(|_| SignatureError::InvalidSignature)
+}
+
+/// Double SHA-256 of the "Bitcoin Signed Message" serialization of `message` (BIP-137)
+fn signed_message_hash(message: &str) -> [u8; 32] {
+    let mut data = Vec::new();
+    write_bytes(&mut data, b"Bitcoin Signed Message:\n");
+    write_bytes(&mut data, message.as_bytes());
+    sha256d(&data)
+}
+
+/// Txid of the BIP-322 virtual `to_spend` transaction, whose single output pays to
+/// `script_pubkey` and whose input commits to the message
+fn to_spend_txid(script_pubkey: &[u8], message: &str) -> [u8; 32] {
+    let mut script_sig = vec![0x00, 0x20]; // OP_0 PUSH32
+    script_sig.extend_from_slice(&tagged_hash("BIP0322-signed-message", message.as_bytes()));
+
+    let mut tx = Vec::new();
+    tx.extend_from_slice(&0u32.to_le_bytes()); // version
+    write_compact_size(&mut tx, 1);
+    tx.extend_from_slice(&[0u8; 32]); // prevout txid
+    tx.extend_from_slice(&u32::MAX.to_le_bytes()); // prevout index
+    write_bytes(&mut tx, &script_sig);
+    tx.extend_from_slice(&0u32.to_le_bytes()); // sequence
+    write_compact_size(&mut tx, 1);
+    tx.extend_from_slice(&0u64.to_le_bytes()); // value
+    write_bytes(&mut tx, script_pubkey);
+    tx.extend_from_slice(&0u32.to_le_bytes()); // locktime
+    sha256d(&tx)
+}
+
+/// BIP-341 key path signature hash of the BIP-322 virtual `to_sign` transaction, spending
+/// `to_spend` into a single `OP_RETURN` output
+fn to_sign_sighash(to_spend_txid: &[u8; 32], script_pubkey: &[u8], hash_type: u8) -> [u8; 32] {
+    let mut prevouts = to_spend_txid.to_vec();
+    prevouts.extend_from_slice(&0u32.to_le_bytes());
+
+    let mut script_pubkeys = Vec::new();
+    write_bytes(&mut script_pubkeys, script_pubkey);
+
+    let mut outputs = 0u64.to_le_bytes().to_vec();
+    write_bytes(&mut outputs, &[0x6a]); // OP_RETURN
+
+    let mut sig_msg = vec![0x00, hash_type]; // epoch, hash type
+    sig_msg.extend_from_slice(&0u32.to_le_bytes()); // version
+    sig_msg.extend_from_slice(&0u32.to_le_bytes()); // locktime
+    sig_msg.extend_from_slice(&Sha256::digest(&prevouts));
+    sig_msg.extend_from_slice(&Sha256::digest(0u64.to_le_bytes())); // amounts
+    sig_msg.extend_from_slice(&Sha256::digest(&script_pubkeys));
+    sig_msg.extend_from_slice(&Sha256::digest(0u32.to_le_bytes())); // sequences
+    sig_msg.extend_from_slice(&Sha256::digest(&outputs));
+    sig_msg.push(0x00); // spend type: key path, no annex
+    sig_msg.extend_from_slice(&0u32.to_le_bytes()); // input index
+    tagged_hash("TapSighash", &sig_msg)
+}
+
+fn tagged_hash(tag: &str, data: &[u8]) -> [u8; 32] {
+    let tag_hash = Sha256::digest(tag.as_bytes());
+    Sha256::new()
+        .chain_update(tag_hash)
+        .chain_update(tag_hash)
+        .chain_update(data)
+        .finalize()
+        .into()
+}
+
+fn sha256d(data: &[u8]) -> [u8; 32] {
+    Sha256::digest(Sha256::digest(data)).into()
+}
+
+fn hash160(data: &[u8]) -> [u8; 20] {
+    Ripemd160::digest(Sha256::digest(data)).into()
+}
+
+fn write_compact_size(out: &mut Vec<u8>, n: u64) {
+    match n {
+        0..=0xfc => out.push(n as u8),
+        0xfd..=0xffff => {
+            out.push(0xfd);
+            out.extend_from_slice(&(n as u16).to_le_bytes());
+        }
+        0x10000..=0xffff_ffff => {
+            out.push(0xfe);
+            out.extend_from_slice(&(n as u32).to_le_bytes());
+        }
+        _ => {
+            out.push(0xff);
+            out.extend_from_slice(&n.to_le_bytes());
+        }
+    }
+}
+
+fn write_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+    write_compact_size(out, bytes.len() as u64);
+    out.extend_from_slice(bytes);
+}
+
+/// Minimal reader for the serialized witness of BIP-322 signatures
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], SignatureError> {
+        if self.0.len() < len {
+            return Err(SignatureError::BadEncoding);
+        }
+        let (head, tail) = self.0.split_at(len);
+        self.0 = tail;
+        Ok(head)
+    }
+
+    fn compact_size(&mut self) -> Result<u64, SignatureError> {
+        let bytes = match self.take(1)?[0] {
+            0xfd => self.take(2)?,
+            0xfe => self.take(4)?,
+            0xff => self.take(8)?,
+            n => return Ok(u64::from(n)),
+        };
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0u64, |n, byte| (n << 8) | u64::from(*byte)))
+    }
+
+    fn bytes(&mut self) -> Result<&'a [u8], SignatureError> {
+        let len = self.compact_size()?;
+        self.take(usize::try_from(len).map_err(|_| SignatureError::BadEncoding)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::signing_key;
+
+    /// BIP-137 signature with the given header base (27, 31, 35 or 39)
+    fn sign(key: &k256::ecdsa::SigningKey, header: u8, message: &str) -> String {
+        let (signature, recovery_id) = key
+            .sign_prehash_recoverable(&signed_message_hash(message))
+            .unwrap();
+        let mut bytes = vec![header + recovery_id.to_byte()];
+        bytes.extend_from_slice(&signature.to_bytes());
+        STANDARD.encode(bytes)
+    }
+
+    /// Reversed, as txids are displayed
+    fn txid(hash: [u8; 32]) -> String {
+        hex::encode(hash.iter().rev().copied().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn matches_the_bip322_vectors() {
+        assert_eq!(
+            hex::encode(tagged_hash("BIP0322-signed-message", b"")),
+            "c90c269c4f8fcbe6880f72a721ddfbf1914268a794cbb21cfafee13770ae19f1"
+        );
+        assert_eq!(
+            hex::encode(tagged_hash("BIP0322-signed-message", b"Hello World")),
+            "f0eb03b1a75ac6d9847f55c624a99169b5dccba2a31f5b23bea77ba270de0a7a"
+        );
+
+        // to_spend of bc1q9vza2e8x573nczrlzms0wvx3gsqjx7vavgkx0l
+        let script_pubkey = hex::decode("00142b05d564e6a7a33c087f16e0f730d1440123799d").unwrap();
+        assert_eq!(
+            txid(to_spend_txid(&script_pubkey, "")),
+            "c5680aa69bb8d860bf82d4e9cd3504b55dde018de765a91bb566283c545a99a7"
+        );
+        assert_eq!(
+            txid(to_spend_txid(&script_pubkey, "Hello World")),
+            "b79d196740ad5217771c1098fc4a4b51e0535c32236c71f1ea4d61a2d603352b"
+        );
+
+        // Single key taproot signature, with an explicit SIGHASH_ALL
+        let address = "bc1ppv609nr0vr25u07u95waq5lucwfm6tde4nydujnu8npg4q75mr5sxq8lt3";
+        let signature = "AUHd69PrJQEv+oKTfZ8l+WROBHuy9HKrbFCJu7U1iK2iiEy1vMU5EfMtjc+VSHM7aU0SDbak5IUZRVno2P5mjSafAQ==";
+        assert_eq!(Bitcoin.verify(address, signature, "Hello World"), Ok(()));
+        assert_eq!(
+            Bitcoin.verify(address, signature, ""),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn matches_bitcoin_core_signmessage() {
+        // From the `signmessage` RPC tests
+        let address = "mpLQjfK79b7CCV4VMJWEWAj5Mpx8Up5zxB";
+        let signature = "INbVnW4e6PeRmsv2Qgu8NuopvrVjkcxob+sX8OcZG0SALhWybUjzMLPdAsXI46YZGb0KQTRii+wWIQzRpG/U+S0=";
+        let message = "This is just a test message";
+        assert_eq!(Bitcoin.verify(address, signature, message), Ok(()));
+        assert_eq!(
+            Bitcoin.verify(address, signature, "This is just a test message."),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn bip137_header_is_bound_to_the_address_type() {
+        let key = signing_key(1);
+        let key_hash = hash160(key.verifying_key().to_encoded_point(true).as_bytes());
+        let mut redeem_script = vec![0x00, 0x14];
+        redeem_script.extend_from_slice(&key_hash);
+
+        let p2pkh = bs58::encode([&[0x00][..], &key_hash].concat())
+            .with_check()
+            .into_string();
+        let p2sh_p2wpkh = bs58::encode([&[0x05][..], &hash160(&redeem_script)].concat())
+            .with_check()
+            .into_string();
+        let p2wpkh = segwit::encode(hrp::BC, segwit::VERSION_0, &key_hash).unwrap();
+
+        let message = "hello";
+        let addresses = [(&p2pkh, 31), (&p2sh_p2wpkh, 35), (&p2wpkh, 39)];
+        for (address, expected) in addresses {
+            for header in [31, 35, 39] {
+                let result = Bitcoin.verify(address, &sign(&key, header, message), message);
+                assert_eq!(result.is_ok(), header == expected, "{address} {header}");
+            }
+        }
+    }
+}
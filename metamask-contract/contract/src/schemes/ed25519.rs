@@ -0,0 +1,41 @@
+use ed25519_dalek::{Signature, VerifyingKey};
+use hex::decode;
+
+use super::{sanitize_hex, SignatureError, SignatureScheme};
+
+/// Solana-style wallets: the identity is the base58 Ed25519 public key, and messages are
+/// signed as raw UTF-8 bytes (`signMessage`)
+pub struct Ed25519;
+
+impl SignatureScheme for Ed25519 {
+    fn validate_key(&self, key: &str) -> Result<(), SignatureError> {
+        parse_key(key).map(|_| ())
+    }
+
+    fn verify(&self, key: &str, signature: &str, message: &str) -> Result<(), SignatureError> {
+        let verifying_key = parse_key(key)?;
+
+        let signature_bytes =
+            decode(sanitize_hex(signature)).map_err(|_| SignatureError::BadHex)?;
+        let signature_bytes: [u8; 64] = signature_bytes
+            .as_slice()
+            .try_into()
+            .map_err(|_| SignatureError::BadLength(signature_bytes.len()))?;
+
+        // Strict verification rejects malleable signatures and weak keys
+        verifying_key
+            .verify_strict(message.as_bytes(), &Signature::from_bytes(&signature_bytes))
+            .map_err(|_| SignatureError::InvalidSignature)
+    }
+}
+
+fn parse_key(key: &str) -> Result<VerifyingKey, SignatureError> {
+    let mut bytes = [0u8; 32];
+    let len = bs58::decode(key)
+        .onto(&mut bytes)
+        .map_err(|_| SignatureError::BadKey)?;
+    if len != bytes.len() {
+        return Err(SignatureError::BadKey);
+    }
+    VerifyingKey::from_bytes(&bytes).map_err(|_| SignatureError::BadKey)
+}
@@ -0,0 +1,299 @@
+use hex::decode;
+use k256::ecdsa::{signature::hazmat::PrehashVerifier, RecoveryId, Signature, VerifyingKey};
+use sha3::{Digest, Keccak256};
+
+use super::{sanitize_hex, SignatureError, SignatureScheme};
+
+/// Ethereum accounts: the identity is the lowercase hex address, and messages are
+/// signed with `personal_sign` (EIP-191)
+pub struct Ethereum;
+
+impl SignatureScheme for Ethereum {
+    fn validate_key(&self, key: &str) -> Result<(), SignatureError> {
+        let address = sanitize_hex(key);
+        if address.len() != 40
+            || !address
+                .chars()
+                .all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c))
+        {
+            return Err(SignatureError::BadKey);
+        }
+        Ok(())
+    }
+
+    fn verify(&self, key: &str, signature: &str, message: &str) -> Result<(), SignatureError> {
+        k256_verifier(key, signature, message)
+    }
+}
+
+/// Checks that `signature_hex` is a `personal_sign` signature of `message` by the Ethereum address `pub_key`
+pub fn k256_verifier(
+    pub_key: &str,
+    signature_hex: &str,
+    message: &str,
+) -> Result<(), SignatureError> {
+    k256_prehash_verifier(pub_key, signature_hex, &eip191_hash(message))
+}
+
+/// Checks that `signature_hex` over the 32 bytes `prehash` was made by the Ethereum address `pub_key`
+pub fn k256_prehash_verifier(
+    pub_key: &str,
+    signature_hex: &str,
+    prehash: &[u8],
+) -> Result<(), SignatureError> {
+    recover_key(pub_key, signature_hex, prehash).map(|_| ())
+}
+
+/// Same as [`k256_verifier`], returning the compressed public key of `pub_key`
+pub fn k256_recover_public_key(
+    pub_key: &str,
+    signature_hex: &str,
+    message: &str,
+) -> Result<[u8; 33], SignatureError> {
+    k256_recover_public_key_prehash(pub_key, signature_hex, &eip191_hash(message))
+}
+
+/// Same as [`k256_prehash_verifier`], returning the compressed public key of `pub_key`
+pub fn k256_recover_public_key_prehash(
+    pub_key: &str,
+    signature_hex: &str,
+    prehash: &[u8],
+) -> Result<[u8; 33], SignatureError> {
+    let key = recover_key(pub_key, signature_hex, prehash)?;
+    key.to_encoded_point(true)
+        .as_bytes()
+        .try_into()
+        .map_err(|_| SignatureError::RecoveryFailed)
+}
+
+/// Checks that `signature_hex` is a `personal_sign` signature of `message` by the compressed
+/// secp256k1 `public_key`. A plain ECDSA verification, cheaper than recovering the signer.
+pub fn k256_key_verifier(
+    public_key: &[u8],
+    signature_hex: &str,
+    message: &str,
+) -> Result<(), SignatureError> {
+    k256_key_prehash_verifier(public_key, signature_hex, &eip191_hash(message))
+}
+
+/// Checks that `signature_hex` over the 32 bytes `prehash` was made by the compressed
+/// secp256k1 `public_key`
+pub fn k256_key_prehash_verifier(
+    public_key: &[u8],
+    signature_hex: &str,
+    prehash: &[u8],
+) -> Result<(), SignatureError> {
+    let (signature, _) = decode_signature(signature_hex)?;
+    let key = VerifyingKey::from_sec1_bytes(public_key).map_err(|_| SignatureError::BadKey)?;
+    key.verify_prehash(prehash, &signature)
+        .map_err(|_| SignatureError::InvalidSignature)
+}
+
+/// Keccak256 of `message` with the Ethereum Signed Message prefix (EIP-191)
+fn eip191_hash(message: &str) -> [u8; 32] {
+    let msg = message.as_bytes();
+    let eth_message = format!(
+        "\x19Ethereum Signed Message:\n{}{}",
+        msg.len(),
+        String::from_utf8_lossy(msg)
+    );
+    Keccak256::digest(eth_message).into()
+}
+
+/// Decodes a hex signature, only accepting its low-s form
+fn decode_signature(signature_hex: &str) -> Result<(Signature, RecoveryId), SignatureError> {
+    let signature_bytes =
+        decode(sanitize_hex(signature_hex)).map_err(|_| SignatureError::BadHex)?;
+    let (signature, recovery_id) = parse_signature(&signature_bytes)?;
+
+    // Only accept the low-s form, so that the same authorization has a single encoding
+    if signature.normalize_s().is_some() {
+        return Err(SignatureError::HighS);
+    }
+    Ok((signature, recovery_id))
+}
+
+/// Recovers the signer of `prehash`, checking that it is the Ethereum address `pub_key`
+fn recover_key(
+    pub_key: &str,
+    signature_hex: &str,
+    prehash: &[u8],
+) -> Result<VerifyingKey, SignatureError> {
+    let pub_key = sanitize_hex(pub_key);
+    let (signature, recovery_id) = decode_signature(signature_hex)?;
+
+    let recovered_key = VerifyingKey::recover_from_prehash(prehash, &signature, recovery_id)
+        .map_err(|_| SignatureError::RecoveryFailed)?;
+
+    let encoded_point = recovered_key.to_encoded_point(false);
+    let pub_key_bytes = encoded_point.as_bytes();
+
+    // Hash the public key (skip the first byte which is always 0x04)
+    let hashed_key = Keccak256::digest(&pub_key_bytes[1..]);
+
+    // Extract the last 20 bytes (Ethereum address format)
+    let recovered_address = hex::encode(&hashed_key[12..]); // Last 20 bytes
+    if recovered_address != pub_key {
+        return Err(SignatureError::AddressMismatch {
+            expected: pub_key.to_string(),
+            recovered: recovered_address,
+        });
+    }
+
+    Ok(recovered_key)
+}
+
+/// Splits an Ethereum signature into its `(r, s)` part and recovery id. Accepted encodings are:
+/// - `r || s || v` with `v` in {0, 1, 27, 28}, or an EIP-155 `v` (`chain_id * 2 + 35 + y_parity`)
+///   encoded big-endian on up to 8 bytes, with no leading zero byte,
+/// - EIP-2098 compact `r || yParityAndS`, where the top bit of `s` holds the y parity.
+fn parse_signature(signature_bytes: &[u8]) -> Result<(Signature, RecoveryId), SignatureError> {
+    let mut rs = [0u8; 64];
+    let y_parity = match signature_bytes.len() {
+        64 => {
+            rs.copy_from_slice(signature_bytes);
+            let y_parity = rs[32] >> 7;
+            rs[32] &= 0x7f;
+            y_parity
+        }
+        65..=72 => {
+            rs.copy_from_slice(&signature_bytes[..64]);
+            let v_bytes = &signature_bytes[64..];
+            // Only the minimal encoding of `v`, so that the same authorization has a single
+            // encoding
+            if v_bytes.len() > 1 && v_bytes[0] == 0 {
+                return Err(SignatureError::BadEncoding);
+            }
+            let v = v_bytes
+                .iter()
+                .fold(0u64, |v, byte| (v << 8) | u64::from(*byte));
+            // Normalize Ethereum's recovery ID
+            match v {
+                0 | 1 => v as u8,
+                27 | 28 => (v - 27) as u8,
+                35.. => ((v - 35) % 2) as u8,
+                _ => return Err(SignatureError::BadRecoveryId(v)),
+            }
+        }
+        len => return Err(SignatureError::BadLength(len)),
+    };
+
+    let recovery_id = RecoveryId::from_byte(y_parity)
+        .ok_or(SignatureError::BadRecoveryId(u64::from(y_parity)))?;
+    let signature = Signature::from_slice(&rs).map_err(|_| SignatureError::RecoveryFailed)?;
+
+    Ok((signature, recovery_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Signer of the EIP-2098 test cases, from the private key `0x1234...1234`
+    const EIP_2098_SIGNER: &str = "0x2e988a386a799f506693793c6a5af6b54dfaabfb";
+
+    /// `(message, r, s, y parity, yParityAndS)` of the EIP-2098 test cases
+    const EIP_2098_VECTORS: [(&str, &str, &str, u8, &str); 2] = [
+        (
+            "Hello World",
+            "68a020a209d3d56c46f38cc50a33f704f4a9a10a59377f8dd762ac66910e9b90",
+            "7e865ad05c4035ab5792787d4a0297a43617ae897930a6fe4d822b8faea52064",
+            0,
+            "7e865ad05c4035ab5792787d4a0297a43617ae897930a6fe4d822b8faea52064",
+        ),
+        (
+            "It's a small(er) world",
+            "9328da16089fcba9bececa81663203989f2df5fe1faa6291a45381c81bd17f76",
+            "139c6d6b623b42da56557e5e734a43dc83345ddfadec52cbe24d0cc64f550793",
+            1,
+            "939c6d6b623b42da56557e5e734a43dc83345ddfadec52cbe24d0cc64f550793",
+        ),
+    ];
+
+    /// Minimal big-endian encoding of `v`
+    fn encode_v(v: u64) -> String {
+        let bytes = v.to_be_bytes();
+        let first = bytes.iter().position(|byte| *byte != 0).unwrap_or(7);
+        hex::encode(&bytes[first..])
+    }
+
+    #[test]
+    fn accepts_a_wallet_signature() {
+        // `personal_sign` of the legacy registration message by MetaMask
+        assert_eq!(
+            k256_verifier(
+                "0x437aa724e898f0ba345852bbbc2e416d9346e1c9",
+                "0x3279f925d976ddfc012a95157f87054717610b4fa08028913ab3139f552e76342d609c11acbca164fb9c64fb5553db3fe34c826c0084fd6bc934dcd154993b0a1b",
+                "hyle registration",
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn accepts_65_byte_and_compact_signatures() {
+        for (message, r, s, y_parity, compact_s) in EIP_2098_VECTORS {
+            for v in [y_parity, 27 + y_parity] {
+                let signature = format!("0x{r}{s}{}", encode_v(u64::from(v)));
+                assert_eq!(k256_verifier(EIP_2098_SIGNER, &signature, message), Ok(()));
+            }
+            let compact = format!("0x{r}{compact_s}");
+            assert_eq!(k256_verifier(EIP_2098_SIGNER, &compact, message), Ok(()));
+            assert_eq!(
+                parse_signature(&decode(format!("{r}{compact_s}")).unwrap()),
+                parse_signature(
+                    &decode(format!("{r}{s}{}", encode_v(27 + u64::from(y_parity)))).unwrap()
+                )
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_eip_155_v() {
+        for (message, r, s, y_parity, _) in EIP_2098_VECTORS {
+            // Mainnet, whose `v` fits a byte, and Sepolia, whose `v` takes 4 bytes
+            for chain_id in [1, 11_155_111] {
+                let v = chain_id * 2 + 35 + u64::from(y_parity);
+                let signature = format!("0x{r}{s}{}", encode_v(v));
+                assert_eq!(k256_verifier(EIP_2098_SIGNER, &signature, message), Ok(()));
+
+                // Only in its minimal encoding
+                let padded = format!("0x{r}{s}00{}", encode_v(v));
+                assert_eq!(
+                    k256_verifier(EIP_2098_SIGNER, &padded, message),
+                    Err(SignatureError::BadEncoding)
+                );
+            }
+        }
+
+        let (message, r, s, ..) = EIP_2098_VECTORS[0];
+        for v in [2, 26, 29, 34] {
+            assert_eq!(
+                k256_verifier(
+                    EIP_2098_SIGNER,
+                    &format!("0x{r}{s}{}", encode_v(v)),
+                    message
+                ),
+                Err(SignatureError::BadRecoveryId(v))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_high_s() {
+        for (message, r, s, y_parity, _) in EIP_2098_VECTORS {
+            let signature = Signature::from_slice(&decode(format!("{r}{s}")).unwrap()).unwrap();
+            let high_s = (-*signature.s()).to_bytes();
+            // The same signature, with the other s and the other y parity
+            let malleated = format!(
+                "0x{r}{}{}",
+                hex::encode(high_s),
+                encode_v(u64::from(28 - y_parity))
+            );
+            assert_eq!(
+                k256_verifier(EIP_2098_SIGNER, &malleated, message),
+                Err(SignatureError::HighS)
+            );
+        }
+    }
+}
@@ -0,0 +1,117 @@
+use borsh::{BorshDeserialize, BorshSerialize};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+pub mod bitcoin;
+pub mod ed25519;
+pub mod ethereum;
+pub mod webauthn;
+
+/// Signature schemes an identity can be registered with. The scheme is stored in the
+/// account, so that all further verifications go through the same [`SignatureScheme`].
+///
+/// To support a new key type, add a variant here and return its implementation
+/// from [`Scheme::verifier`].
+#[derive(
+    BorshSerialize,
+    BorshDeserialize,
+    Serialize,
+    Deserialize,
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    Eq,
+    PartialEq,
+)]
+pub enum Scheme {
+    /// secp256k1 keys identified by their Ethereum address, signing with `personal_sign`
+    #[default]
+    Ethereum,
+    /// P-256 passkeys identified by their compressed public key, signing WebAuthn assertions
+    WebAuthn,
+    /// Ed25519 keys identified by their base58 public key, as used by Solana wallets
+    Ed25519,
+    /// secp256k1 keys identified by their Bitcoin address, signing with BIP-137 or BIP-322
+    Bitcoin,
+}
+
+impl Scheme {
+    /// All the supported schemes
+    pub const ALL: [Scheme; 4] = [
+        Scheme::Ethereum,
+        Scheme::WebAuthn,
+        Scheme::Ed25519,
+        Scheme::Bitcoin,
+    ];
+
+    /// Returns the implementation of this scheme
+    pub fn verifier(&self) -> &'static dyn SignatureScheme {
+        match self {
+            Scheme::Ethereum => &ethereum::Ethereum,
+            Scheme::WebAuthn => &webauthn::WebAuthn,
+            Scheme::Ed25519 => &ed25519::Ed25519,
+            Scheme::Bitcoin => &bitcoin::Bitcoin,
+        }
+    }
+}
+
+/// A kind of key identities can be registered with
+pub trait SignatureScheme {
+    /// Checks that `key`, the identity without its contract suffix, is a key of this scheme
+    fn validate_key(&self, key: &str) -> Result<(), SignatureError>;
+
+    /// Checks that `signature` is a signature of `message` by `key`
+    fn verify(&self, key: &str, signature: &str, message: &str) -> Result<(), SignatureError>;
+}
+
+/// Reasons a signature can be rejected by a [`SignatureScheme`]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SignatureError {
+    /// The identity key is not a valid key for the scheme
+    BadKey,
+    /// The signature is not valid hex
+    BadHex,
+    /// The signature is not valid base64, or its content is malformed
+    BadEncoding,
+    /// The signature does not have the expected length
+    BadLength(usize),
+    /// The `v` value is neither 0, 1, 27, 28 nor an EIP-155 value (35 and above)
+    BadRecoveryId(u64),
+    /// No public key could be recovered from the signature
+    RecoveryFailed,
+    /// The signature has a high `s` value, i.e. it is the malleated twin of a valid signature
+    HighS,
+    /// The signature is well-formed but was not made by the key over the message
+    InvalidSignature,
+    /// The WebAuthn assertion is malformed or does not match the message
+    BadAssertion(&'static str),
+    /// The signature was made by another address
+    AddressMismatch { expected: String, recovered: String },
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SignatureError::BadKey => write!(f, "invalid identity key for this scheme"),
+            SignatureError::BadHex => write!(f, "signature is not valid hex"),
+            SignatureError::BadEncoding => write!(f, "malformed signature encoding"),
+            SignatureError::BadLength(len) => write!(f, "unexpected signature length {len}"),
+            SignatureError::BadRecoveryId(v) => write!(f, "invalid recovery id {v}"),
+            SignatureError::RecoveryFailed => {
+                write!(f, "could not recover a public key from the signature")
+            }
+            SignatureError::HighS => write!(f, "signature has a non-canonical high s value"),
+            SignatureError::InvalidSignature => write!(f, "signature does not match"),
+            SignatureError::BadAssertion(reason) => write!(f, "invalid assertion: {reason}"),
+            SignatureError::AddressMismatch {
+                expected,
+                recovered,
+            } => write!(f, "signature made by 0x{recovered}, expected 0x{expected}"),
+        }
+    }
+}
+
+pub(crate) fn sanitize_hex(hex_str: &str) -> &str {
+    hex_str.strip_prefix("0x").unwrap_or(hex_str)
+}
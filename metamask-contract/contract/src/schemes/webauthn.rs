@@ -0,0 +1,206 @@
+use hex::decode;
+use p256::ecdsa::{signature::Verifier, Signature, VerifyingKey};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use super::{sanitize_hex, SignatureError, SignatureScheme};
+
+/// "User present" bit of the authenticator data flags
+const USER_PRESENT: u8 = 0x01;
+
+/// Passkeys: the identity is the hex compressed P-256 public key, and messages are signed
+/// through a WebAuthn assertion whose challenge is the SHA-256 of the message
+pub struct WebAuthn;
+
+/// WebAuthn assertion, as carried JSON-encoded in the `signature` field of the actions
+#[derive(Deserialize)]
+struct Assertion {
+    /// Hex-encoded `authenticatorData`
+    authenticator_data: String,
+    /// `clientDataJSON`, as returned by the authenticator
+    client_data_json: String,
+    /// Hex-encoded DER ECDSA signature
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct ClientData {
+    #[serde(rename = "type")]
+    kind: String,
+    challenge: String,
+}
+
+impl SignatureScheme for WebAuthn {
+    fn validate_key(&self, key: &str) -> Result<(), SignatureError> {
+        parse_key(key).map(|_| ())
+    }
+
+    fn verify(&self, key: &str, signature: &str, message: &str) -> Result<(), SignatureError> {
+        let verifying_key = parse_key(key)?;
+
+        let assertion: Assertion = serde_json::from_str(signature)
+            .map_err(|_| SignatureError::BadAssertion("malformed assertion"))?;
+
+        let mut signed_data = decode(sanitize_hex(&assertion.authenticator_data))
+            .map_err(|_| SignatureError::BadHex)?;
+        // rpIdHash (32 bytes) || flags (1 byte) || signCount (4 bytes) || ...
+        if signed_data.len() < 37 {
+            return Err(SignatureError::BadAssertion("authenticator data too short"));
+        }
+        if signed_data[32] & USER_PRESENT == 0 {
+            return Err(SignatureError::BadAssertion("user not present"));
+        }
+
+        let client_data: ClientData = serde_json::from_str(&assertion.client_data_json)
+            .map_err(|_| SignatureError::BadAssertion("malformed client data"))?;
+        if client_data.kind != "webauthn.get" {
+            return Err(SignatureError::BadAssertion("not an assertion"));
+        }
+        if client_data.challenge != challenge(message) {
+            return Err(SignatureError::BadAssertion(
+                "challenge does not match message",
+            ));
+        }
+
+        let signature_bytes =
+            decode(sanitize_hex(&assertion.signature)).map_err(|_| SignatureError::BadHex)?;
+        let signature = Signature::from_der(&signature_bytes)
+            .map_err(|_| SignatureError::BadLength(signature_bytes.len()))?;
+        // Authenticators do not normalize s, and the nonce already prevents replays
+        let signature = signature.normalize_s().unwrap_or(signature);
+
+        signed_data.extend_from_slice(&Sha256::digest(assertion.client_data_json.as_bytes()));
+        verifying_key
+            .verify(&signed_data, &signature)
+            .map_err(|_| SignatureError::InvalidSignature)
+    }
+}
+
+/// Challenge the assertion must carry for `message`: the unpadded base64url SHA-256 of the message
+pub fn challenge(message: &str) -> String {
+    base64url(&Sha256::digest(message.as_bytes()))
+}
+
+fn parse_key(key: &str) -> Result<VerifyingKey, SignatureError> {
+    let key = sanitize_hex(key);
+    let bytes = decode(key).map_err(|_| SignatureError::BadKey)?;
+    // Only the lowercase compressed encoding, so that a key maps to a single identity
+    if bytes.len() != 33 || hex::encode(&bytes) != key {
+        return Err(SignatureError::BadKey);
+    }
+    VerifyingKey::from_sec1_bytes(&bytes).map_err(|_| SignatureError::BadKey)
+}
+
+fn base64url(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_";
+
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let buffer = chunk.iter().enumerate().fold(0u32, |acc, (i, byte)| {
+            acc | u32::from(*byte) << (16 - 8 * i)
+        });
+        for i in 0..=chunk.len() {
+            encoded.push(ALPHABET[(buffer >> (18 - 6 * i) & 0x3f) as usize] as char);
+        }
+    }
+    encoded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Compressed public key of the P-256 private key `0x1111...1111`
+    const KEY: &str = "020217e617f0b6443928278f96999e69a23a4f2c152bdf6d6cdf66e5b80282d4ed";
+    /// rpIdHash of `localhost`, user present and verified, signCount 1
+    const AUTHENTICATOR_DATA: &str =
+        "49960de5880e8c687434170f6476605b8fe4aeb9a28632c7995cf3ba831d97630500000001";
+    const CLIENT_DATA_JSON: &str = r#"{"type":"webauthn.get","challenge":"87_s7VWcRsCth_9JI2Xh2Yt2KFULbh6NRIFdwPrKWlI","origin":"http://localhost:5173","crossOrigin":false}"#;
+    /// RFC 6979 signature of the assertion, left with a high s as authenticators do
+    const SIGNATURE: &str = "304502203f61a7a0b451f1800e5f3ffd45cd7d0c8a17521cad3b60046935b0e1d7bd6ba0022100ceb9957058195e96ed7bd23cc562f00775699990ebac5a5ca0ccb27be76caee7";
+
+    fn assertion(authenticator_data: &str, client_data_json: &str) -> String {
+        serde_json::json!({
+            "authenticator_data": authenticator_data,
+            "client_data_json": client_data_json,
+            "signature": SIGNATURE,
+        })
+        .to_string()
+    }
+
+    #[test]
+    fn accepts_an_assertion() {
+        assert_eq!(
+            challenge("hyle registration"),
+            "87_s7VWcRsCth_9JI2Xh2Yt2KFULbh6NRIFdwPrKWlI"
+        );
+        assert_eq!(
+            WebAuthn.verify(
+                KEY,
+                &assertion(AUTHENTICATOR_DATA, CLIENT_DATA_JSON),
+                "hyle registration"
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_assertions() {
+        let signature = assertion(AUTHENTICATOR_DATA, CLIENT_DATA_JSON);
+        assert_eq!(
+            WebAuthn.verify(KEY, &signature, "hyle registration 2"),
+            Err(SignatureError::BadAssertion(
+                "challenge does not match message"
+            ))
+        );
+
+        let absent = AUTHENTICATOR_DATA.replace("630500", "630400");
+        assert_eq!(
+            WebAuthn.verify(
+                KEY,
+                &assertion(&absent, CLIENT_DATA_JSON),
+                "hyle registration"
+            ),
+            Err(SignatureError::BadAssertion("user not present"))
+        );
+
+        let creation = CLIENT_DATA_JSON.replace("webauthn.get", "webauthn.create");
+        assert_eq!(
+            WebAuthn.verify(
+                KEY,
+                &assertion(AUTHENTICATOR_DATA, &creation),
+                "hyle registration"
+            ),
+            Err(SignatureError::BadAssertion("not an assertion"))
+        );
+
+        // Same challenge, but not the signed client data
+        let origin = CLIENT_DATA_JSON.replace("5173", "5174");
+        assert_eq!(
+            WebAuthn.verify(
+                KEY,
+                &assertion(AUTHENTICATOR_DATA, &origin),
+                "hyle registration"
+            ),
+            Err(SignatureError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn encodes_unpadded_base64url() {
+        // RFC 4648 test vectors, without padding
+        for (bytes, encoded) in [
+            ("", ""),
+            ("f", "Zg"),
+            ("fo", "Zm8"),
+            ("foo", "Zm9v"),
+            ("foob", "Zm9vYg"),
+            ("fooba", "Zm9vYmE"),
+            ("foobar", "Zm9vYmFy"),
+        ] {
+            assert_eq!(base64url(bytes.as_bytes()), encoded);
+        }
+        // The URL-safe characters, in place of `+` and `/`
+        assert_eq!(base64url(&[0xfb, 0xef, 0xff]), "--__");
+    }
+}
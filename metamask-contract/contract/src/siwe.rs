@@ -0,0 +1,507 @@
+use sdk::ContractName;
+use sha3::{Digest, Keccak256};
+
+const PREAMBLE_SUFFIX: &str = " wants you to sign in with your Ethereum account:";
+
+/// An EIP-4361 "Sign-In with Ethereum" message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SiweMessage {
+    /// RFC 3986 authority requesting the signing, optionally prefixed by its scheme
+    pub domain: String,
+    /// EIP-55 checksummed address of the signer
+    pub address: String,
+    pub statement: Option<String>,
+    pub uri: String,
+    pub version: String,
+    /// EIP-155 chain id of the wallet
+    pub chain_id: u64,
+    pub nonce: String,
+    /// Milliseconds since the Unix epoch
+    pub issued_at: u128,
+    /// Milliseconds since the Unix epoch
+    pub expiration_time: Option<u128>,
+    /// Milliseconds since the Unix epoch
+    pub not_before: Option<u128>,
+    pub request_id: Option<String>,
+    pub resources: Vec<String>,
+}
+
+/// Resource a SIWE message must list to register an identity on `contract_name`, deployed
+/// on the network `network_id`
+pub fn registration_resource(contract_name: &ContractName, network_id: &str) -> String {
+    format!("hyle://{network_id}/{contract_name}")
+}
+
+impl SiweMessage {
+    /// Parses a message following the EIP-4361 ABNF
+    pub fn parse(message: &str) -> Result<Self, String> {
+        let mut lines = message.split('\n').peekable();
+
+        let domain = lines
+            .next()
+            .and_then(|line| line.strip_suffix(PREAMBLE_SUFFIX))
+            .filter(|domain| !domain.is_empty() && !domain.contains(char::is_whitespace))
+            .ok_or("Invalid SIWE preamble")?
+            .to_string();
+        let address = lines.next().ok_or("Missing SIWE address")?.to_string();
+        if !is_checksummed_address(&address) {
+            return Err(format!("{address} is not an EIP-55 checksummed address"));
+        }
+        if lines.next() != Some("") {
+            return Err("Missing empty line after the SIWE address".to_string());
+        }
+
+        let statement = match lines.peek() {
+            // Some implementations omit the empty line when there is no statement
+            Some(line) if line.starts_with("URI: ") => None,
+            Some(&"") => {
+                lines.next();
+                None
+            }
+            Some(_) => {
+                let statement = lines.next().map(str::to_string);
+                if lines.next() != Some("") {
+                    return Err("Missing empty line after the SIWE statement".to_string());
+                }
+                statement
+            }
+            None => return Err("Truncated SIWE message".to_string()),
+        };
+
+        let uri = field(&mut lines, "URI")?.to_string();
+        let version = field(&mut lines, "Version")?.to_string();
+        let chain_id = field(&mut lines, "Chain ID")?
+            .parse()
+            .map_err(|_| "Invalid SIWE chain id")?;
+        let nonce = field(&mut lines, "Nonce")?.to_string();
+        let issued_at = timestamp(field(&mut lines, "Issued At")?)?;
+        let expiration_time = optional_field(&mut lines, "Expiration Time")
+            .map(timestamp)
+            .transpose()?;
+        let not_before = optional_field(&mut lines, "Not Before")
+            .map(timestamp)
+            .transpose()?;
+        let request_id = optional_field(&mut lines, "Request ID").map(str::to_string);
+
+        let mut resources = Vec::new();
+        if lines.next_if_eq(&"Resources:").is_some() {
+            while let Some(resource) = lines.next_if(|line| line.starts_with("- ")) {
+                resources.push(resource[2..].to_string());
+            }
+        }
+        if lines.next().is_some() {
+            return Err("Unexpected content at the end of the SIWE message".to_string());
+        }
+
+        Ok(SiweMessage {
+            domain,
+            address,
+            statement,
+            uri,
+            version,
+            chain_id,
+            nonce,
+            issued_at,
+            expiration_time,
+            not_before,
+            request_id,
+            resources,
+        })
+    }
+
+    /// Checks that the message registers `pub_key` on `contract_name` from the chain
+    /// `chain_id`, and that it is valid at `now` (milliseconds since the Unix epoch). Without
+    /// `now`, messages with validity bounds are rejected. The domain is not checked: the
+    /// contract does not know which site the message comes from, the resource binds it to
+    /// the contract instead.
+    pub fn validate_registration(
+        &self,
+        pub_key: &str,
+        contract_name: &ContractName,
+        network_id: &str,
+        chain_id: u64,
+        now: Option<u128>,
+    ) -> Result<(), String> {
+        if self.address.to_lowercase().trim_start_matches("0x") != pub_key.trim_start_matches("0x")
+        {
+            return Err(format!(
+                "SIWE address {} does not match identity {pub_key}",
+                self.address
+            ));
+        }
+        if self.version != "1" {
+            return Err(format!("Unsupported SIWE version {}", self.version));
+        }
+        if self.chain_id != chain_id {
+            return Err(format!(
+                "SIWE message is signed on chain {}, expected {chain_id}",
+                self.chain_id
+            ));
+        }
+        if self.uri.is_empty() || self.uri.contains(char::is_whitespace) {
+            return Err(format!("Invalid SIWE URI {}", self.uri));
+        }
+        if self.nonce.len() < 8 || !self.nonce.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(format!("Invalid SIWE nonce {}", self.nonce));
+        }
+
+        let resource = registration_resource(contract_name, network_id);
+        if !self.resources.contains(&resource) {
+            return Err(format!(
+                "SIWE message does not list the resource {resource}"
+            ));
+        }
+
+        if now.is_some_and(|now| self.issued_at > now) {
+            return Err("SIWE message is issued in the future".to_string());
+        }
+        if self.expiration_time.is_some() || self.not_before.is_some() {
+            let now = now.ok_or("SIWE validity bounds require the transaction context")?;
+            if self
+                .expiration_time
+                .is_some_and(|expiration| now >= expiration)
+            {
+                return Err("SIWE message expired".to_string());
+            }
+            if self.not_before.is_some_and(|not_before| now < not_before) {
+                return Err("SIWE message is not valid yet".to_string());
+            }
+        }
+        Ok(())
+    }
+}
+
+fn field<'a>(
+    lines: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>,
+    name: &str,
+) -> Result<&'a str, String> {
+    optional_field(lines, name).ok_or(format!("Missing SIWE field {name}"))
+}
+
+fn optional_field<'a>(
+    lines: &mut std::iter::Peekable<impl Iterator<Item = &'a str>>,
+    name: &str,
+) -> Option<&'a str> {
+    let line = lines.next_if(|line| {
+        line.strip_prefix(name)
+            .is_some_and(|rest| rest.starts_with(": "))
+    })?;
+    Some(&line[name.len() + 2..])
+}
+
+/// Checks that `address` is `0x` followed by 40 hex chars, capitalized following EIP-55
+fn is_checksummed_address(address: &str) -> bool {
+    let Some(hex) = address.strip_prefix("0x") else {
+        return false;
+    };
+    if hex.len() != 40 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return false;
+    }
+
+    let hash = Keccak256::digest(hex.to_lowercase().as_bytes());
+    hex.chars().enumerate().all(|(i, c)| {
+        let nibble = (hash[i / 2] >> (4 * (1 - i % 2))) & 0x0f;
+        !c.is_ascii_alphabetic() || c.is_ascii_uppercase() == (nibble >= 8)
+    })
+}
+
+fn timestamp(value: &str) -> Result<u128, String> {
+    parse_rfc3339(value).ok_or(format!("Invalid SIWE timestamp {value}"))
+}
+
+/// Parses an RFC 3339 `date-time` into milliseconds since the Unix epoch
+fn parse_rfc3339(value: &str) -> Option<u128> {
+    let bytes = value.as_bytes();
+    let number = |range: std::ops::Range<usize>| -> Option<i64> {
+        let digits = bytes.get(range)?;
+        digits.iter().try_fold(0i64, |n, digit| {
+            digit
+                .is_ascii_digit()
+                .then(|| n * 10 + i64::from(digit - b'0'))
+        })
+    };
+
+    let (year, month, day) = (number(0..4)?, number(5..7)?, number(8..10)?);
+    let (hour, minute, second) = (number(11..13)?, number(14..16)?, number(17..19)?);
+    if bytes.get(4) != Some(&b'-')
+        || bytes.get(7) != Some(&b'-')
+        || !matches!(bytes.get(10), Some(b'T' | b't'))
+        || bytes.get(13) != Some(&b':')
+        || bytes.get(16) != Some(&b':')
+    {
+        return None;
+    }
+    if !(1..=12).contains(&month)
+        || day < 1
+        || day > days_in_month(year, month)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // Fraction of seconds, truncated to milliseconds
+    let mut rest = &value[19..];
+    let mut millis = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(fraction.len());
+        if len == 0 {
+            return None;
+        }
+        millis = format!("{:0<3}", &fraction[..len.min(3)]).parse().ok()?;
+        rest = &fraction[len..];
+    }
+
+    let offset = match rest.as_bytes() {
+        [b'Z' | b'z'] => 0,
+        [sign @ (b'+' | b'-'), h1, h2, b':', m1, m2] => {
+            let digits = [h1, h2, m1, m2];
+            if !digits.iter().all(|digit| digit.is_ascii_digit()) {
+                return None;
+            }
+            let hours = i64::from((h1 - b'0') * 10 + (h2 - b'0'));
+            let minutes = i64::from((m1 - b'0') * 10 + (m2 - b'0'));
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            let offset = hours * 3600 + minutes * 60;
+            if *sign == b'+' {
+                offset
+            } else {
+                -offset
+            }
+        }
+        _ => return None,
+    };
+
+    let seconds =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    u128::try_from(seconds * 1000 + millis).ok()
+}
+
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// Number of days since 1970-01-01 of a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Example message of EIP-4361
+    const MESSAGE: &str = "example.com wants you to sign in with your Ethereum account:
+0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed
+
+I accept the ExampleOrg Terms of Service: https://example.com/tos
+
+URI: https://example.com/login
+Version: 1
+Chain ID: 1
+Nonce: 32891756
+Issued At: 2021-09-30T16:25:24Z
+Resources:
+- ipfs://bafybeiemxf5abjwjbikoz4mc3a3dla6ual3jsgpdr4cjr3oz3evfyavhwq/
+- https://example.com/my-web2-claim.json";
+
+    const ISSUED_AT: u128 = 1_633_019_124_000;
+
+    fn registration(extra_fields: &str) -> SiweMessage {
+        let message = MESSAGE.replace(
+            "Issued At: 2021-09-30T16:25:24Z\n",
+            &format!("Issued At: 2021-09-30T16:25:24Z\n{extra_fields}"),
+        );
+        let mut message = SiweMessage::parse(&message).unwrap();
+        message
+            .resources
+            .push(registration_resource(&"mmid".into(), "hyle-devnet"));
+        message
+    }
+
+    fn validate(message: &SiweMessage, chain_id: u64, now: Option<u128>) -> Result<(), String> {
+        message.validate_registration(
+            "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+            &"mmid".into(),
+            "hyle-devnet",
+            chain_id,
+            now,
+        )
+    }
+
+    #[test]
+    fn parses_the_eip_4361_example() {
+        let message = SiweMessage::parse(MESSAGE).unwrap();
+        assert_eq!(message.domain, "example.com");
+        assert_eq!(
+            message.address,
+            "0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed"
+        );
+        assert_eq!(
+            message.statement.as_deref(),
+            Some("I accept the ExampleOrg Terms of Service: https://example.com/tos")
+        );
+        assert_eq!(message.uri, "https://example.com/login");
+        assert_eq!(message.version, "1");
+        assert_eq!(message.chain_id, 1);
+        assert_eq!(message.nonce, "32891756");
+        assert_eq!(message.issued_at, ISSUED_AT);
+        assert_eq!(message.expiration_time, None);
+        assert_eq!(message.not_before, None);
+        assert_eq!(message.request_id, None);
+        assert_eq!(message.resources.len(), 2);
+    }
+
+    #[test]
+    fn parses_optional_fields() {
+        let message = SiweMessage::parse(&MESSAGE.replace(
+            "Issued At: 2021-09-30T16:25:24Z\n",
+            "Issued At: 2021-09-30T16:25:24Z\nExpiration Time: 2021-10-01T00:00:00Z\nNot Before: 2021-09-30T00:00:00Z\nRequest ID: 42\n",
+        ))
+        .unwrap();
+        assert_eq!(message.expiration_time, Some(1_633_046_400_000));
+        assert_eq!(message.not_before, Some(1_632_960_000_000));
+        assert_eq!(message.request_id.as_deref(), Some("42"));
+
+        let message = SiweMessage::parse(&MESSAGE.replace(
+            "\nResources:\n- ipfs://bafybeiemxf5abjwjbikoz4mc3a3dla6ual3jsgpdr4cjr3oz3evfyavhwq/\n- https://example.com/my-web2-claim.json",
+            "",
+        ))
+        .unwrap();
+        assert!(message.resources.is_empty());
+    }
+
+    #[test]
+    fn parses_messages_without_statement() {
+        let without_statement = MESSAGE.replace(
+            "I accept the ExampleOrg Terms of Service: https://example.com/tos\n\n",
+            "",
+        );
+        assert_eq!(
+            SiweMessage::parse(&without_statement).unwrap().statement,
+            None
+        );
+        // With the empty line of the statement kept
+        let empty_statement = MESSAGE.replace(
+            "I accept the ExampleOrg Terms of Service: https://example.com/tos\n",
+            "",
+        );
+        assert_eq!(
+            SiweMessage::parse(&empty_statement).unwrap().statement,
+            None
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_messages() {
+        let malformed = [
+            MESSAGE.replace(" wants you to sign in", " wants you to sign"),
+            MESSAGE.replace("example.com wants", "example .com wants"),
+            MESSAGE.replace("0x5aAeb", "0x5aaeb"),
+            MESSAGE.replace("0x5aAeb6053F3E94C9b9A09f33669435E7Ef1BeAed", "0x5aAeb6053F"),
+            MESSAGE.replace("https://example.com/tos\n\n", "https://example.com/tos\n"),
+            MESSAGE.replace("Version: 1\n", ""),
+            MESSAGE.replace("Chain ID: 1", "Chain ID: one"),
+            MESSAGE.replace("Nonce: 32891756\nIssued At", "Issued At"),
+            MESSAGE.replace("2021-09-30T16:25:24Z", "yesterday"),
+            MESSAGE.replace("URI: ", "URI:"),
+            format!("{MESSAGE}\nextra"),
+        ];
+        for message in malformed {
+            assert!(SiweMessage::parse(&message).is_err(), "{message}");
+        }
+    }
+
+    #[test]
+    fn parses_rfc3339_timestamps() {
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(0));
+        assert_eq!(parse_rfc3339("2021-09-30T16:25:24Z"), Some(ISSUED_AT));
+        assert_eq!(parse_rfc3339("2021-09-30t16:25:24z"), Some(ISSUED_AT));
+        assert_eq!(
+            parse_rfc3339("2021-09-30T18:25:24.5+02:00"),
+            Some(ISSUED_AT + 500)
+        );
+        assert_eq!(
+            parse_rfc3339("2021-09-30T14:25:24.123456-02:00"),
+            Some(ISSUED_AT + 123)
+        );
+        assert_eq!(
+            parse_rfc3339("2024-02-29T00:00:00Z"),
+            Some(1_709_164_800_000)
+        );
+        assert_eq!(parse_rfc3339("2000-03-01T00:00:00Z"), Some(951_868_800_000));
+    }
+
+    #[test]
+    fn rejects_invalid_rfc3339_timestamps() {
+        let invalid = [
+            "2021-09-30T16:25:24",
+            "2021-09-30 16:25:24Z",
+            "2021-09-30T16:25Z",
+            "2021-13-01T00:00:00Z",
+            "2021-02-29T00:00:00Z",
+            "1900-02-29T00:00:00Z",
+            "2021-09-31T00:00:00Z",
+            "2021-09-30T24:00:00Z",
+            "2021-09-30T16:60:00Z",
+            "2021-09-30T16:25:24.Z",
+            "2021-09-30T16:25:24+2:00",
+            "2021-09-30T16:25:24+24:00",
+            "2021-09-30T16:25:24Z ",
+            "1969-12-31T23:59:59Z",
+            "+021-09-30T16:25:24Z",
+        ];
+        for value in invalid {
+            assert_eq!(parse_rfc3339(value), None, "{value}");
+        }
+    }
+
+    #[test]
+    fn validates_registrations() {
+        let message = registration("");
+        assert_eq!(validate(&message, 1, None), Ok(()));
+        assert_eq!(validate(&message, 1, Some(ISSUED_AT)), Ok(()));
+
+        assert!(validate(&message, 5, None).is_err());
+        assert!(validate(&message, 1, Some(ISSUED_AT - 1)).is_err());
+        assert!(message
+            .validate_registration(
+                "0x5aaeb6053f3e94c9b9a09f33669435e7ef1beaed",
+                &"mmid".into(),
+                "hyle-testnet",
+                1,
+                None,
+            )
+            .is_err());
+        assert!(SiweMessage::parse(MESSAGE)
+            .map(|message| validate(&message, 1, None))
+            .unwrap()
+            .is_err());
+    }
+
+    #[test]
+    fn checks_validity_bounds() {
+        let message = registration(
+            "Expiration Time: 2021-10-01T00:00:00Z\nNot Before: 2021-09-30T17:00:00Z\n",
+        );
+        assert!(validate(&message, 1, None).is_err());
+        assert!(validate(&message, 1, Some(ISSUED_AT)).is_err());
+        assert_eq!(validate(&message, 1, Some(1_633_021_200_000)), Ok(()));
+        assert!(validate(&message, 1, Some(1_633_046_400_000)).is_err());
+    }
+}
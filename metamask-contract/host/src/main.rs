@@ -1,23 +1,34 @@
-use axum::{extract::Json, http::Method, routing::post, Router};
+use anyhow::Context;
+use axum::{
+    extract::Json,
+    http::{Method, StatusCode},
+    routing::post,
+    Router,
+};
 use clap::{Parser, Subcommand};
 use client_sdk::helpers::risc0::Risc0Prover;
-use contract_identity::IdentityContractState;
-use hex::decode;
-use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use contract_identity::actions::IdentityAction;
+use contract_identity::admin::{parse_program_id, Admin, AdminCommand};
+use contract_identity::commitment::IdentityContractRoot;
+use contract_identity::message::{self, Deadline};
+use contract_identity::migration::VersionedState;
+use contract_identity::{k256_verifier, IdentityContractState, LegacyMessages};
 use sdk::api::APIRegisterContract;
 use sdk::ContractInput;
 use sdk::TxHash;
-use sdk::{HyleContract, Identity, ProofTransaction};
+use sdk::{BlobTransaction, HyleContract, Identity, ProofTransaction};
 use serde::Deserialize;
-use sha3::Digest;
-use sha3::Keccak256;
 use std::env;
+use std::time::Duration;
 use tower_http::cors::{Any, CorsLayer};
 
 // These constants represent the RISC-V ELF and the image ID generated by risc0-build.
 // The ELF is used for proving and the ID is used for verification.
 use methods_identity::{GUEST_ELF, GUEST_ID};
 
+/// Number of seconds to wait for a transaction to land in a block before proving it
+const TX_CONTEXT_ATTEMPTS: u32 = 30;
+
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 #[command(propagate_version = true)]
@@ -33,13 +44,69 @@ struct Cli {
 
     #[arg(long, default_value = "mmid")]
     pub contract_name: String,
+
+    /// Chain id of the node, part of the transaction context proofs are checked against.
+    /// Required to prove transactions.
+    #[arg(long)]
+    pub chain_id: Option<u128>,
 }
 
 #[derive(Subcommand)]
 enum Commands {
     RunServer,
-    RegisterContract {},
-    ValidateSignature { account: String, signature: String },
+    RegisterContract {
+        #[arg(long, default_value = contract_identity::DEFAULT_NETWORK_ID)]
+        network_id: String,
+
+        /// Also accept signatures made over the messages that were not bound to a contract and a network, up to this block height
+        #[arg(long)]
+        legacy_messages_until: Option<u64>,
+
+        /// EIP-155 chain id of the Sign-In with Ethereum registration messages
+        #[arg(long, default_value_t = contract_identity::DEFAULT_SIWE_CHAIN_ID)]
+        siwe_chain_id: u64,
+
+        /// Identity key allowed to pause registrations and verifications, and to freeze identities
+        #[arg(long, value_parser = parse_admin)]
+        admin: Option<String>,
+    },
+    ValidateSignature {
+        account: String,
+        signature: String,
+
+        #[arg(long, default_value = contract_identity::DEFAULT_NETWORK_ID)]
+        network_id: String,
+    },
+    VerifyMessage {
+        /// Identity key (the identity without its contract suffix) the transaction is sent by
+        account: String,
+        nonce: u128,
+        tx_hash: String,
+
+        /// Last block height at which the signature is valid
+        #[arg(long, conflicts_with = "valid_until_timestamp")]
+        valid_until_block: Option<u64>,
+
+        /// Last timestamp (in milliseconds) at which the signature is valid
+        #[arg(long)]
+        valid_until_timestamp: Option<u128>,
+    },
+    /// Prints the message the admin signs to upgrade the contract to a new program
+    ProposeUpgrade {
+        /// Nonce of the admin identity
+        nonce: u128,
+
+        /// Program id to upgrade to (hex), defaults to the program of this build
+        #[arg(long)]
+        program_id: Option<String>,
+    },
+    /// Sends and proves the upgrade signed by the admin. The proof is made by the program of
+    /// this build, so it must be run from the build of the program currently registered.
+    SubmitUpgrade {
+        program_id: String,
+        nonce: u128,
+        signature: String,
+    },
 }
 
 #[derive(Deserialize)]
@@ -59,14 +126,24 @@ async fn main() {
 
     let cli = Cli::parse();
 
-    let client = client_sdk::rest_client::NodeApiHttpClient::new(cli.host).unwrap();
+    let client = client_sdk::rest_client::NodeApiHttpClient::new(cli.host.clone()).unwrap();
 
     let contract_name = &cli.contract_name;
 
     match cli.command {
-        Commands::RegisterContract {} => {
+        Commands::RegisterContract {
+            network_id,
+            legacy_messages_until,
+            siwe_chain_id,
+            admin,
+        } => {
             // Build initial state of contract
-            let initial_state = IdentityContractState::new();
+            let legacy_messages = match legacy_messages_until {
+                Some(height) => LegacyMessages::Until(height),
+                None => LegacyMessages::Rejected,
+            };
+            let initial_state =
+                IdentityContractState::new(network_id, legacy_messages, siwe_chain_id, admin);
             println!("Initial state: {:?}", initial_state);
 
             // Send the transaction to register the contract
@@ -80,22 +157,126 @@ async fn main() {
 
             println!("✅ Register contract tx sent. Tx hash: {}", res);
         }
-        Commands::ValidateSignature { signature, account } => {
+        Commands::ValidateSignature {
+            signature,
+            account,
+            network_id,
+        } => {
             //Example \`personal_sign\` message
             //0xc4b1989d045e1f9aacc448032a7e278780de9a1c1735984c8d4e95cc1840715b3255b0cb791df3c5c137fa22773f9f6976b96418581e44f3fdf1e6ec395f6b661b
             //0x437aa724e898f0ba345852bbbc2e416d9346e1c9
 
-            //hyle registration
+            //hyle registration (legacy registration message)
             //0x3279f925d976ddfc012a95157f87054717610b4fa08028913ab3139f552e76342d609c11acbca164fb9c64fb5553db3fe34c826c0084fd6bc934dcd154993b0a1b
             //0x437aa724e898f0ba345852bbbc2e416d9346e1c9
 
-            let res = k256_verifier(account, signature);
-
-            if res {
-                println!("✅ Signature successfully validated.");
-            } else {
-                println!("❌ Signature invalid");
+            let message = message::registration_message(&contract_name.clone().into(), &network_id);
+            match k256_verifier(&account, &signature, &message) {
+                Ok(()) => println!("✅ Signature successfully validated."),
+                Err(err) => println!("❌ Signature invalid: {err}"),
+            }
+        }
+        Commands::VerifyMessage {
+            account,
+            nonce,
+            tx_hash,
+            valid_until_block,
+            valid_until_timestamp,
+        } => {
+            // Only the root is committed once the contract holds identities
+            let network_id = contract_root(&client, contract_name).await.network_id;
+
+            let indexer = client_sdk::rest_client::IndexerApiHttpClient::new(cli.host).unwrap();
+
+            let mut blobs: Vec<sdk::Blob> = indexer
+                .get_blobs_by_tx_hash(&TxHash(tx_hash))
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|blob| sdk::Blob {
+                    contract_name: blob.contract_name.clone().into(),
+                    data: sdk::BlobData(blob.data),
+                })
+                .collect();
+
+            // The identity blob itself is not part of the signed payload
+            if let Some(index) = blobs
+                .iter()
+                .position(|blob| blob.contract_name.0 == *contract_name)
+            {
+                blobs.remove(index);
             }
+
+            println!(
+                "{}",
+                message::verify_message(
+                    &contract_name.clone().into(),
+                    &network_id,
+                    &account,
+                    nonce,
+                    valid_until_block
+                        .map(Deadline::BlockHeight)
+                        .or(valid_until_timestamp.map(Deadline::Timestamp)),
+                    &blobs
+                )
+            );
+        }
+
+        Commands::ProposeUpgrade { nonce, program_id } => {
+            let root = contract_root(&client, contract_name).await;
+            let admin = root.admin.expect("The contract has no admin");
+            let program_id = match program_id {
+                Some(program_id) => parse_program_id(&program_id).unwrap(),
+                None => sdk::ProgramId(sdk::to_u8_array(&GUEST_ID).to_vec()),
+            };
+            // The admin signs the canonical hex of the parsed id
+            let program_id = hex::encode(program_id.0);
+
+            println!("Program id: {program_id}");
+            println!(
+                "{}",
+                message::admin_message(
+                    &contract_name.clone().into(),
+                    &root.network_id,
+                    &admin.identity.to_string(),
+                    &AdminCommand::UpgradeProgram { program_id },
+                    nonce
+                )
+            );
+        }
+        Commands::SubmitUpgrade {
+            program_id,
+            nonce,
+            signature,
+        } => {
+            let root = contract_root(&client, contract_name).await;
+            let admin = root.admin.expect("The contract has no admin");
+            let identity: Identity = format!("{}.{contract_name}", admin.identity).into();
+            let program_id = hex::encode(parse_program_id(&program_id).unwrap().0);
+
+            let action = IdentityAction::Admin {
+                nonce,
+                command: AdminCommand::UpgradeProgram { program_id },
+                signature,
+            };
+            let blobs = vec![action.as_blob(contract_name.clone().into())];
+            let tx_hash = client
+                .send_tx_blob(&BlobTransaction::new(identity.clone(), blobs.clone()))
+                .await
+                .unwrap();
+            println!("✅ Upgrade tx sent. Tx hash: {}", tx_hash);
+
+            let proof_tx_hash = prove_tx(
+                &cli.host,
+                cli.chain_id,
+                contract_name,
+                identity,
+                tx_hash,
+                blobs,
+            )
+            .await
+            .unwrap();
+            println!("✅ Upgrade proof tx sent. Tx hash: {}", proof_tx_hash);
         }
 
         Commands::RunServer => {
@@ -104,6 +285,33 @@ async fn main() {
     }
 }
 
+fn parse_admin(identity: &str) -> Result<String, String> {
+    Admin::validate_identity(identity).map(|_| identity.to_string())
+}
+
+/// Committed root of the contract, migrated to the current version of the state
+async fn contract_root(
+    client: &client_sdk::rest_client::NodeApiHttpClient,
+    contract_name: &str,
+) -> IdentityContractRoot {
+    let commitment = client
+        .get_contract(&contract_name.into())
+        .await
+        .unwrap()
+        .state;
+    migrated_root(&commitment).unwrap()
+}
+
+/// Root committed by `commitment`, migrated to the current version of the state
+fn migrated_root(commitment: &sdk::StateCommitment) -> anyhow::Result<IdentityContractRoot> {
+    let mut state: VersionedState = borsh::from_slice(&commitment.0)?;
+    state.migrate();
+    match state {
+        VersionedState::V3(root) => Ok(root),
+        _ => anyhow::bail!("State was not migrated"),
+    }
+}
+
 // Function to start the REST server
 async fn run_server() {
     let cors = CorsLayer::new()
@@ -123,19 +331,9 @@ async fn run_server() {
 }
 
 // Handler for /prove endpoint
-async fn prove(Json(request): Json<ProveRequest>) -> Json<TxHash> {
+async fn prove(Json(request): Json<ProveRequest>) -> Result<Json<TxHash>, (StatusCode, String)> {
     let cli = Cli::parse();
-    let client = client_sdk::rest_client::NodeApiHttpClient::new(cli.host.clone()).unwrap();
-    let indexer = client_sdk::rest_client::IndexerApiHttpClient::new(cli.host).unwrap();
-    let prover = Risc0Prover::new(GUEST_ELF);
-
-    let mut initial_state: IdentityContractState = client
-        .get_contract(&request.contract_name.clone().into())
-        .await
-        .unwrap()
-        .state
-        .try_into()
-        .unwrap();
+    let indexer = client_sdk::rest_client::IndexerApiHttpClient::new(cli.host.clone()).unwrap();
 
     let blobs: Vec<sdk::Blob> = indexer
         .get_blobs_by_tx_hash(&request.tx_hash)
@@ -148,21 +346,75 @@ async fn prove(Json(request): Json<ProveRequest>) -> Json<TxHash> {
         })
         .collect();
 
-    println!("Initial state {:?}", initial_state.clone());
-    println!("identity {:?}", request.identity.clone());
     println!("signature {:?}", request.signature.clone());
-    println!("contract_name {:?}", request.contract_name.clone());
-    println!("tx_hash {:?}", request.tx_hash.clone());
 
-    let inputs = ContractInput {
-        state: initial_state.as_bytes().unwrap(),
-        identity: request.identity.clone().into(),
-        tx_hash: request.tx_hash.clone().into(),
+    prove_tx(
+        &cli.host,
+        cli.chain_id,
+        &request.contract_name,
+        request.identity,
+        request.tx_hash,
+        blobs,
+    )
+    .await
+    .map(Json)
+    .map_err(|err| (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()))
+}
+
+/// Proves the transaction `tx_hash` of `blobs` and sends its proof
+async fn prove_tx(
+    host: &str,
+    chain_id: Option<u128>,
+    contract_name: &str,
+    identity: Identity,
+    tx_hash: TxHash,
+    blobs: Vec<sdk::Blob>,
+) -> anyhow::Result<TxHash> {
+    let chain_id = chain_id.context("Pass the chain id of the node with --chain-id")?;
+    let client = client_sdk::rest_client::NodeApiHttpClient::new(host.to_string()).unwrap();
+    let indexer = client_sdk::rest_client::IndexerApiHttpClient::new(host.to_string()).unwrap();
+    let prover = Risc0Prover::new(GUEST_ELF);
+
+    let commitment = client.get_contract(&contract_name.into()).await?.state;
+    // Only the Merkle root of a migrated state is on-chain, its full state is kept by the
+    // indexer
+    let mut initial_state = match IdentityContractState::try_from(commitment.clone()) {
+        Ok(state) => state,
+        Err(_) => {
+            reqwest::get(format!("{host}/v1/indexer/contract/{contract_name}/state"))
+                .await?
+                .json()
+                .await?
+        }
+    };
+    // A stale state would prove against a root the node rejects
+    let committed_root = migrated_root(&commitment)?.root;
+    if initial_state.root().root != committed_root {
+        anyhow::bail!(
+            "The state of {contract_name} does not match its committed root {}",
+            hex::encode(committed_root)
+        );
+    }
+
+    println!("identity {:?}", identity.clone());
+    println!("contract_name {:?}", contract_name);
+    println!("tx_hash {:?}", tx_hash.clone());
+
+    // Deadlines, session key expiries and recovery delays are checked against it
+    let tx_ctx = tx_context(&indexer, &tx_hash, chain_id).await?;
+
+    let mut inputs = ContractInput {
+        state: commitment.0,
+        identity,
+        tx_hash,
         private_input: vec![],
-        blobs: blobs.clone(),
+        blobs,
         index: sdk::BlobIndex(0),
-        tx_ctx: None,
+        tx_ctx: Some(tx_ctx),
     };
+    // The leaves of the state touched by the transaction, checked against the root
+    let witness = initial_state.witness(&inputs).map_err(anyhow::Error::msg)?;
+    inputs.private_input = borsh::to_vec(&witness)?;
 
     println!("inputs {:?}", inputs.clone());
 
@@ -171,63 +423,37 @@ async fn prove(Json(request): Json<ProveRequest>) -> Json<TxHash> {
         println!("Error: {:?}", e);
     }
 
-    let proof = prover.prove(inputs).await.unwrap();
+    let proof = prover.prove(inputs).await?;
     let proof_tx = ProofTransaction {
         proof,
-        contract_name: request.contract_name.clone().into(),
+        contract_name: contract_name.into(),
     };
 
-    let proof_tx_hash: TxHash = client.send_tx_proof(&proof_tx).await.unwrap();
     //println!("Proof transaction sent: {:?}", proof.clone());
-    Json(proof_tx_hash)
+    client.send_tx_proof(&proof_tx).await
 }
 
-pub fn k256_verifier(pub_key: String, signature_hex: String) -> bool {
-    let sig = &String::from(signature_hex);
-    let pb = &String::from(pub_key);
-
-    let signature_hex_str = sanitize_hex(sig);
-    let pub_key_str = sanitize_hex(pb);
-
-    let msg = "hyle registration".as_bytes();
-
-    // Apply Ethereum Signed Message Prefix (EIP-191)
-    let eth_message = format!(
-        "\x19Ethereum Signed Message:\n{}{}",
-        msg.len(),
-        String::from_utf8_lossy(msg)
-    );
-
-    let signature_bytes = decode(&signature_hex_str).expect("Invalid hex string");
-    let recovery_id_byte = signature_bytes.last().copied().expect("Signature is empty");
-    // Normalize Ethereum's recovery ID
-    let recovery_id_byte = if recovery_id_byte >= 27 {
-        recovery_id_byte - 27
-    } else {
-        recovery_id_byte
-    };
-    let recovery_id = RecoveryId::try_from(recovery_id_byte).expect("Wrong recover id byte");
-
-    let signature = Signature::from_slice(&signature_bytes[..64]).expect("wrong signature");
-
-    let recovered_key = VerifyingKey::recover_from_digest(
-        Keccak256::new_with_prefix(eth_message),
-        &signature,
-        recovery_id,
-    )
-    .expect("wrong recovered key");
-
-    let encoded_point = recovered_key.to_encoded_point(false);
-    let pub_key_bytes = encoded_point.as_bytes();
-
-    // 6️⃣ Hash the public key (skip the first byte which is always 0x04)
-    let hashed_key = Keccak256::digest(&pub_key_bytes[1..]);
-
-    // 7️⃣ Extract the last 20 bytes (Ethereum address format)
-    let recovered_address = &hashed_key[12..]; // Last 20 bytes
-    hex::encode(recovered_address) == pub_key_str
-}
-
-fn sanitize_hex(hex_str: &str) -> &str {
-    hex_str.strip_prefix("0x").unwrap_or(hex_str)
+/// Context of the transaction `tx_hash`, from the lane and the block it landed in, waiting
+/// for it to be included. The node rejects proofs of another context.
+async fn tx_context(
+    indexer: &client_sdk::rest_client::IndexerApiHttpClient,
+    tx_hash: &TxHash,
+    chain_id: u128,
+) -> anyhow::Result<sdk::TxContext> {
+    for _ in 0..TX_CONTEXT_ATTEMPTS {
+        let tx = indexer.get_transaction_with_hash(tx_hash).await.ok();
+        if let Some((Some(block_hash), Some(lane_id))) = tx.map(|tx| (tx.block_hash, tx.lane_id)) {
+            let block = indexer.get_block_by_hash(&block_hash).await?;
+            return Ok(sdk::TxContext {
+                lane_id,
+                block_hash,
+                block_height: sdk::BlockHeight(block.height),
+                // Block timestamps are in milliseconds
+                timestamp: u128::try_from(block.timestamp)?,
+                chain_id,
+            });
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    anyhow::bail!("Transaction {} was not included in a block", tx_hash.0)
 }
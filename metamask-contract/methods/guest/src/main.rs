@@ -7,13 +7,13 @@ use sdk::guest::execute;
 use sdk::guest::GuestEnv;
 use sdk::guest::Risc0Env;
 
-use contract_identity::IdentityContractState;
+use contract_identity::migration::VersionedState;
 
 risc0_zkvm::guest::entry!(main);
 
 fn main() {
     let env = Risc0Env {};
     let input = env.read();
-    let (_, output) = execute::<IdentityContractState>(&input);
+    let (_, output) = execute::<VersionedState>(&input);
     env.commit(&output);
 }